@@ -198,8 +198,10 @@ async function fetchRangeBytesWithMOQ(
 
 export const requestInitWithMOQ = async (): Promise<Uint8Array> => {
   try {
+    // The init segment is the only object of its own track, so it never
+    // collides with media group 0.
     const namespace = Tuple.fromUtf8Path("moqtail");
-    const fullTrackName = FullTrackName.tryNew(namespace, "demo");
+    const fullTrackName = FullTrackName.tryNew(namespace, "demo/init");
 
     const initStart = new Location(0n, 0n);
     const initEnd = new Location(0n, 0n);
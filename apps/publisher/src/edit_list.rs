@@ -0,0 +1,195 @@
+// Copyright 2025 The MOQtail Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Edit list (`elst`) semantics per ISO/IEC 14496-12 8.6.6: mapping a track's
+//! media timeline onto the movie's presentation timeline.
+
+use mp4::ElstBox;
+use serde::{Deserialize, Serialize};
+
+/// One `elst` entry, with all times converted to the track's media timescale.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditSegment {
+    /// Where the segment starts on the presentation timeline.
+    pub presentation_start: u64,
+    /// Length of the segment on the presentation timeline. `None` when the
+    /// entry has a zero duration, which fragmented files use for "until the
+    /// end of the media".
+    pub duration: Option<u64>,
+    /// First media time the segment shows, or `None` for an empty edit.
+    pub media_time: Option<u64>,
+    /// Integer part of the playback rate; 0 dwells on `media_time`.
+    pub media_rate: i16,
+}
+
+impl EditSegment {
+    /// Presentation time of `media_time` if this segment shows it.
+    fn present(&self, media_time: u64) -> Option<u64> {
+        let start = self.media_time?;
+        let elapsed = media_time.checked_sub(start)?;
+        match (self.media_rate.max(0) as u64, self.duration) {
+            (0, _) => (elapsed == 0).then_some(self.presentation_start),
+            (rate, Some(d)) if elapsed >= d.saturating_mul(rate) => None,
+            (rate, _) => Some(self.presentation_start + elapsed / rate),
+        }
+    }
+}
+
+/// A track's edit list. An empty list is the identity mapping.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EditList {
+    pub segments: Vec<EditSegment>,
+}
+
+impl EditList {
+    pub fn from_elst(elst: &ElstBox, movie_timescale: u32, media_timescale: u32) -> Self {
+        let empty_media_time = if elst.version == 1 {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        // segment_duration is in the movie timescale, media_time in the
+        // media timescale.
+        let to_media =
+            |d: u64| (d as u128 * media_timescale as u128 / movie_timescale.max(1) as u128) as u64;
+
+        let mut presentation_start = 0u64;
+        let segments = elst
+            .entries
+            .iter()
+            .map(|e| {
+                let duration = (e.segment_duration > 0).then(|| to_media(e.segment_duration));
+                let segment = EditSegment {
+                    presentation_start,
+                    duration,
+                    media_time: (e.media_time != empty_media_time).then_some(e.media_time),
+                    media_rate: e.media_rate as i16,
+                };
+                presentation_start = presentation_start.saturating_add(duration.unwrap_or(0));
+                segment
+            })
+            .collect();
+        EditList { segments }
+    }
+
+    /// Maps a media (composition) time to the presentation timeline. Media
+    /// times that no edit shows, such as AAC priming samples, are clamped to
+    /// the start of the earliest segment showing later media, or to the end
+    /// of the presentation when there is none.
+    pub fn to_presentation(&self, media_time: u64) -> u64 {
+        if self.segments.is_empty() {
+            return media_time;
+        }
+        if let Some(t) = self.segments.iter().find_map(|s| s.present(media_time)) {
+            return t;
+        }
+        self.segments
+            .iter()
+            .filter(|s| s.media_time.is_some_and(|m| m > media_time))
+            .map(|s| s.presentation_start)
+            .min()
+            .unwrap_or_else(|| {
+                self.segments
+                    .last()
+                    .map_or(0, |s| s.presentation_start + s.duration.unwrap_or(0))
+            })
+    }
+
+    /// Offset that turns media time into presentation time under the first
+    /// segment that shows media: the length of any leading empty edits minus
+    /// that segment's media_time. Zero without an edit list.
+    pub fn presentation_offset(&self) -> i64 {
+        self.segments
+            .iter()
+            .find_map(|s| s.media_time.map(|m| s.presentation_start as i64 - m as i64))
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::presentation_ns;
+    use mp4::ElstEntry;
+    use std::collections::HashMap;
+
+    const MOVIE_TIMESCALE: u32 = 1000;
+    const MEDIA_TIMESCALE: u32 = 90_000;
+    const MS: u64 = 1_000_000;
+
+    fn entry(duration_ms: u64, media_time: u64, media_rate: u16) -> ElstEntry {
+        ElstEntry {
+            segment_duration: duration_ms,
+            media_time,
+            media_rate,
+            media_rate_fraction: 0,
+        }
+    }
+
+    /// `presentation_ns` of track 1 under `entries`, in milliseconds.
+    fn present_ms(version: u8, entries: Vec<ElstEntry>, media_time: u64) -> u64 {
+        let elst = ElstBox {
+            version,
+            flags: 0,
+            entries,
+        };
+        let timescale = HashMap::from([(1, MEDIA_TIMESCALE)]);
+        let edits = HashMap::from([(
+            1,
+            EditList::from_elst(&elst, MOVIE_TIMESCALE, MEDIA_TIMESCALE),
+        )]);
+        presentation_ns(1, media_time, &timescale, &edits) / MS
+    }
+
+    #[test]
+    fn no_edits_is_the_identity() {
+        let timescale = HashMap::from([(1, MEDIA_TIMESCALE)]);
+        assert_eq!(
+            presentation_ns(1, 135_000, &timescale, &HashMap::new()),
+            1500 * MS
+        );
+        assert_eq!(present_ms(0, vec![], 135_000), 1500);
+    }
+
+    #[test]
+    fn empty_edit_delays_the_media() {
+        for (version, empty) in [(0, u32::MAX as u64), (1, u64::MAX)] {
+            let entries = || vec![entry(500, empty, 1), entry(0, 0, 1)];
+            assert_eq!(present_ms(version, entries(), 0), 500);
+            assert_eq!(present_ms(version, entries(), 90_000), 1500);
+        }
+    }
+
+    #[test]
+    fn rate_zero_dwells_on_its_media_time() {
+        // Hold the frame at 0.1 s for one second, then play on from it
+        let entries = || vec![entry(1000, 9000, 0), entry(0, 9000, 1)];
+        assert_eq!(present_ms(0, entries(), 9000), 0);
+        assert_eq!(present_ms(0, entries(), 18_000), 1100);
+        // Media before the dwell is not shown and starts with it
+        assert_eq!(present_ms(0, entries(), 0), 0);
+    }
+
+    #[test]
+    fn multiple_segments_skip_media_no_edit_shows() {
+        // Play 0-1 s, then 2-3 s of the media
+        let entries = || vec![entry(1000, 0, 1), entry(1000, 180_000, 1)];
+        assert_eq!(present_ms(0, entries(), 45_000), 500);
+        assert_eq!(present_ms(0, entries(), 225_000), 1500);
+        // 1.5 s of media is cut; it lands where the next shown media starts
+        assert_eq!(present_ms(0, entries(), 135_000), 1000);
+        // Media past every segment is held at the end of the presentation
+        assert_eq!(present_ms(0, entries(), 300_000), 2000);
+    }
+}
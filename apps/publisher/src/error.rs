@@ -0,0 +1,131 @@
+// Copyright 2025 The MOQtail Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::indexer::LocationSpan;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::fmt;
+use warp::http::StatusCode;
+
+/// Failures of the HTTP routes. Handlers reject with these and
+/// `handle_rejection` turns them into a status code and a JSON body.
+#[derive(Debug)]
+pub enum PublisherError {
+    /// The request names something the index does not have.
+    NotFound(String),
+    /// The requested span lies outside the content, or holds none.
+    /// `available` is the span the index does have, if any.
+    RangeNotSatisfiable {
+        message: String,
+        available: Option<LocationSpan>,
+    },
+    /// The requested span ends before it starts.
+    InvertedRange {
+        message: String,
+        available: Option<LocationSpan>,
+    },
+    /// Malformed request.
+    BadRequest(String),
+    /// Reading the media file failed.
+    Io(std::io::Error),
+    /// An object could not be encoded for the response.
+    Serialization(String),
+}
+
+impl PublisherError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            PublisherError::NotFound(_) => StatusCode::NOT_FOUND,
+            PublisherError::RangeNotSatisfiable { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
+            PublisherError::InvertedRange { .. } | PublisherError::BadRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            PublisherError::Io(_) | PublisherError::Serialization(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn available(&self) -> Option<LocationSpan> {
+        match self {
+            PublisherError::RangeNotSatisfiable { available, .. }
+            | PublisherError::InvertedRange { available, .. } => *available,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PublisherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublisherError::NotFound(msg)
+            | PublisherError::RangeNotSatisfiable { message: msg, .. }
+            | PublisherError::InvertedRange { message: msg, .. }
+            | PublisherError::BadRequest(msg) => f.write_str(msg),
+            PublisherError::Io(e) => write!(f, "Failed to read media: {}", e),
+            PublisherError::Serialization(msg) => write!(f, "Failed to serialize: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PublisherError {}
+
+// Also gives `From<PublisherError> for warp::Rejection`, so handlers can use `?`.
+impl warp::reject::Reject for PublisherError {}
+
+impl From<std::io::Error> for PublisherError {
+    fn from(e: std::io::Error) -> Self {
+        PublisherError::Io(e)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorBody {
+    status: u16,
+    error: String,
+    /// Content the index holds, for range errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available: Option<LocationSpan>,
+}
+
+/// Maps rejections, ours and warp's own, to a JSON error response.
+pub async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
+    let (status, error, available) = if let Some(e) = err.find::<PublisherError>() {
+        (e.status(), e.to_string(), e.available())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "No such route".to_string(), None)
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string(), None)
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string(), None)
+    } else {
+        println!("Unhandled rejection: {:?}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error".to_string(),
+            None,
+        )
+    };
+
+    if status.is_server_error() {
+        println!("Request failed with {}: {}", status, error);
+    }
+    let body = ErrorBody {
+        status: status.as_u16(),
+        error,
+        available,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
@@ -0,0 +1,1096 @@
+// Copyright 2025 The MOQtail Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! On-the-fly fragmentation of progressive (moov+mdat) MP4 files.
+//!
+//! Fragments are cut from the `stbl` sample tables at index time. Their moof
+//! boxes are only generated when a fragment is read, so the index stays small
+//! and the media file is never rewritten.
+
+use crate::edit_list::EditList;
+use crate::indexer::{self, Frag, FragLayout, GroupingPolicy, Sample, SampleRange, TrackDefaults};
+use bytes::BufMut;
+use mp4::{MoovBox, StblBox};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+const BOX_HEADER_LEN: u64 = 8;
+const MFHD_LEN: u64 = 16;
+const TFHD_LEN: u64 = 16;
+const TFDT_LEN: u64 = 20;
+// header, version/flags, sample_count, data_offset
+const TRUN_FIXED_LEN: u64 = 20;
+// duration, size, flags, composition offset
+const TRUN_SAMPLE_LEN: u64 = 16;
+const TREX_LEN: u64 = 32;
+
+pub const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x02_0000;
+const TRUN_DATA_OFFSET: u32 = 0x00_0001;
+const TRUN_SAMPLE_DURATION: u32 = 0x00_0100;
+const TRUN_SAMPLE_SIZE: u32 = 0x00_0200;
+const TRUN_SAMPLE_FLAGS: u32 = 0x00_0400;
+const TRUN_SAMPLE_CTS: u32 = 0x00_0800;
+
+// sample_depends_on = 2 (independent) for sync samples; sample_depends_on = 1
+// plus sample_is_non_sync_sample for everything else.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+/// Boxes dropped from a progressive `stbl` because they describe samples that
+/// now live in fragments. Empty `stts`/`stsc`/`stsz`/`stco` are written in
+/// their place.
+const SAMPLE_TABLE_BOXES: [&[u8; 4]; 13] = [
+    b"stts", b"ctts", b"stsc", b"stsz", b"stz2", b"stco", b"co64", b"stss", b"stsh", b"sdtp",
+    b"sbgp", b"saiz", b"saio",
+];
+
+/// Length of the moof generated for a fragment of `sample_count` samples.
+pub fn moof_len(sample_count: usize) -> u64 {
+    BOX_HEADER_LEN
+        + MFHD_LEN
+        + BOX_HEADER_LEN
+        + TFHD_LEN
+        + TFDT_LEN
+        + TRUN_FIXED_LEN
+        + TRUN_SAMPLE_LEN * sample_count as u64
+}
+
+/// Length of the mdat header needed for `payload_len` bytes of sample data.
+pub fn mdat_header_len(payload_len: u64) -> u64 {
+    if payload_len + BOX_HEADER_LEN > u32::MAX as u64 {
+        2 * BOX_HEADER_LEN
+    } else {
+        BOX_HEADER_LEN
+    }
+}
+
+/// Cuts every track of a progressive file into fragments and returns them
+/// together with the samples they reference. Under GOP-aligned policies,
+/// tracks with sync sample tables get one fragment per GOP; every other track
+/// is cut into time buckets. Groups are assigned afterwards by the indexer.
+pub fn progressive_frags(
+    moov: &MoovBox,
+    timescale: &HashMap<u32, u32>,
+    edits: &HashMap<u32, EditList>,
+    policy: GroupingPolicy,
+) -> Result<(Vec<Frag>, Vec<Sample>), Box<dyn std::error::Error>> {
+    let mut frags = Vec::new();
+    let mut samples = Vec::new();
+    let bucket_ns = indexer::bucket_ns(policy);
+
+    for trak in &moov.traks {
+        let track_id = trak.tkhd.track_id;
+        let stbl = &trak.mdia.minf.stbl;
+        let track_samples = expand_samples(stbl).map_err(|e| format!("track {track_id}: {e}"))?;
+        let cut_at_sync = policy.is_gop_aligned() && stbl.stss.is_some();
+        let base = samples.len();
+
+        let mut sequence_number = 0u32;
+        let mut decode_time = 0u64;
+        let mut run: Option<(u64, usize, u64)> = None; // (bucket, first sample, tfdt)
+        for (i, sample) in track_samples.iter().enumerate() {
+            let bucket =
+                indexer::presentation_ns(track_id, decode_time, timescale, edits) / bucket_ns;
+            let starts_fragment = match run {
+                None => true,
+                Some(_) if cut_at_sync => sample.is_sync,
+                Some((b, _, _)) => b != bucket,
+            };
+            if starts_fragment {
+                if let Some((_, first, tfdt)) = run {
+                    sequence_number += 1;
+                    frags.push(synthesized_frag(
+                        track_id,
+                        tfdt,
+                        base + first,
+                        &track_samples[first..i],
+                        sequence_number,
+                    ));
+                }
+                run = Some((bucket, i, decode_time));
+            }
+            decode_time += sample.duration as u64;
+        }
+        if let Some((_, first, tfdt)) = run {
+            sequence_number += 1;
+            frags.push(synthesized_frag(
+                track_id,
+                tfdt,
+                base + first,
+                &track_samples[first..],
+                sequence_number,
+            ));
+        }
+
+        samples.extend(track_samples);
+    }
+
+    Ok((frags, samples))
+}
+
+fn synthesized_frag(
+    track_id: u32,
+    tfdt: u64,
+    first_sample: usize,
+    samples: &[Sample],
+    sequence_number: u32,
+) -> Frag {
+    let payload_len: u64 = samples.iter().map(|s| s.size as u64).sum();
+    let moof_len = moof_len(samples.len());
+    Frag {
+        track_id,
+        tfdt,
+        group: 0,
+        object: 0,
+        moof_start: 0,
+        mdat_start: moof_len,
+        mdat_size: mdat_header_len(payload_len) + payload_len,
+        keyframe: samples.first().is_some_and(|s| s.is_sync),
+        samples: Some(SampleRange {
+            first: first_sample,
+            count: samples.len(),
+        }),
+        layout: FragLayout::Synthesized { sequence_number },
+    }
+}
+
+/// Expands the run-length coded sample tables of one track into one entry per
+/// sample, in decode order.
+fn expand_samples(stbl: &StblBox) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+    let count = stbl.stsz.sample_count as usize;
+    let chunk_offsets: Vec<u64> = match (&stbl.stco, &stbl.co64) {
+        (Some(stco), _) => stco.entries.iter().map(|&o| o as u64).collect(),
+        (None, Some(co64)) => co64.entries.clone(),
+        (None, None) => return Err("stbl has neither stco nor co64".into()),
+    };
+
+    let mut samples = Vec::with_capacity(count);
+    let stsc = &stbl.stsc.entries;
+    'chunks: for (i, entry) in stsc.iter().enumerate() {
+        let last_chunk = stsc
+            .get(i + 1)
+            .map(|next| next.first_chunk as usize)
+            .unwrap_or(chunk_offsets.len() + 1);
+        for chunk in entry.first_chunk as usize..last_chunk {
+            let mut offset = *chunk
+                .checked_sub(1)
+                .and_then(|c| chunk_offsets.get(c))
+                .ok_or_else(|| format!("stsc references missing chunk {chunk}"))?;
+            for _ in 0..entry.samples_per_chunk {
+                if samples.len() == count {
+                    break 'chunks;
+                }
+                let size = if stbl.stsz.sample_size != 0 {
+                    stbl.stsz.sample_size
+                } else {
+                    *stbl
+                        .stsz
+                        .sample_sizes
+                        .get(samples.len())
+                        .ok_or("stsz has fewer sizes than samples")?
+                };
+                samples.push(Sample {
+                    offset,
+                    size,
+                    duration: 0,
+                    cts_offset: 0,
+                    is_sync: stbl.stss.is_none(),
+                });
+                offset += size as u64;
+            }
+        }
+    }
+    if samples.len() != count {
+        return Err(format!(
+            "stsc maps {} samples but stsz declares {}",
+            samples.len(),
+            count
+        )
+        .into());
+    }
+
+    let durations = stbl
+        .stts
+        .entries
+        .iter()
+        .flat_map(|e| std::iter::repeat_n(e.sample_delta, e.sample_count as usize));
+    for (sample, duration) in samples.iter_mut().zip(durations) {
+        sample.duration = duration;
+    }
+
+    if let Some(ctts) = &stbl.ctts {
+        let offsets = ctts
+            .entries
+            .iter()
+            .flat_map(|e| std::iter::repeat_n(e.sample_offset, e.sample_count as usize));
+        for (sample, cts_offset) in samples.iter_mut().zip(offsets) {
+            sample.cts_offset = cts_offset;
+        }
+    }
+
+    if let Some(stss) = &stbl.stss {
+        for &number in &stss.entries {
+            if let Some(sample) = (number as usize)
+                .checked_sub(1)
+                .and_then(|i| samples.get_mut(i))
+            {
+                sample.is_sync = true;
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+/// Generates the moof for a synthesized fragment and appends an mdat holding
+/// its samples, read from wherever they are stored in the progressive file.
+pub fn read_synthesized<R: Read + Seek>(
+    r: &mut R,
+    frag: &Frag,
+    sequence_number: u32,
+    samples: &[Sample],
+) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(frag.byte_len() as usize);
+    let mdat_header_len = frag.mdat_size - samples.iter().map(|s| s.size as u64).sum::<u64>();
+    let data_offset = frag.mdat_start - frag.moof_start + mdat_header_len;
+    write_moof(
+        &mut out,
+        frag.track_id,
+        sequence_number,
+        frag.tfdt,
+        data_offset as i32,
+        samples,
+    );
+
+    if mdat_header_len == BOX_HEADER_LEN {
+        out.put_u32(frag.mdat_size as u32);
+        out.put_slice(b"mdat");
+    } else {
+        out.put_u32(1);
+        out.put_slice(b"mdat");
+        out.put_u64(frag.mdat_size);
+    }
+
+    // Samples of a track are usually laid out back to back within a chunk, so
+    // contiguous runs are read with a single seek.
+    let mut i = 0;
+    while i < samples.len() {
+        let start = samples[i].offset;
+        let mut end = start + samples[i].size as u64;
+        i += 1;
+        while i < samples.len() && samples[i].offset == end {
+            end += samples[i].size as u64;
+            i += 1;
+        }
+        let pos = out.len();
+        out.resize(pos + (end - start) as usize, 0);
+        r.seek(SeekFrom::Start(start))?;
+        r.read_exact(&mut out[pos..])?;
+    }
+
+    Ok(out)
+}
+
+fn write_moof(
+    out: &mut Vec<u8>,
+    track_id: u32,
+    sequence_number: u32,
+    base_media_decode_time: u64,
+    data_offset: i32,
+    samples: &[Sample],
+) {
+    let trun_len = TRUN_FIXED_LEN + TRUN_SAMPLE_LEN * samples.len() as u64;
+
+    out.put_u32(moof_len(samples.len()) as u32);
+    out.put_slice(b"moof");
+
+    out.put_u32(MFHD_LEN as u32);
+    out.put_slice(b"mfhd");
+    out.put_u32(0);
+    out.put_u32(sequence_number);
+
+    out.put_u32((BOX_HEADER_LEN + TFHD_LEN + TFDT_LEN + trun_len) as u32);
+    out.put_slice(b"traf");
+
+    out.put_u32(TFHD_LEN as u32);
+    out.put_slice(b"tfhd");
+    out.put_u32(TFHD_DEFAULT_BASE_IS_MOOF);
+    out.put_u32(track_id);
+
+    out.put_u32(TFDT_LEN as u32);
+    out.put_slice(b"tfdt");
+    out.put_u32(1 << 24); // version 1: 64-bit decode time
+    out.put_u64(base_media_decode_time);
+
+    out.put_u32(trun_len as u32);
+    out.put_slice(b"trun");
+    // Version 1 so composition offsets are signed.
+    out.put_u32(
+        (1 << 24)
+            | TRUN_DATA_OFFSET
+            | TRUN_SAMPLE_DURATION
+            | TRUN_SAMPLE_SIZE
+            | TRUN_SAMPLE_FLAGS
+            | TRUN_SAMPLE_CTS,
+    );
+    out.put_u32(samples.len() as u32);
+    out.put_i32(data_offset);
+    for sample in samples {
+        out.put_u32(sample.duration);
+        out.put_u32(sample.size);
+        out.put_u32(if sample.is_sync {
+            SYNC_SAMPLE_FLAGS
+        } else {
+            NON_SYNC_SAMPLE_FLAGS
+        });
+        out.put_i32(sample.cts_offset);
+    }
+}
+
+/// A box inside an in-memory buffer.
+struct RawBox<'a> {
+    name: [u8; 4],
+    payload: &'a [u8],
+    bytes: &'a [u8],
+}
+
+/// Splits `buf` into the boxes it contains. Parsing stops at the first box
+/// whose declared size does not fit in `buf`.
+fn child_boxes(buf: &[u8]) -> Vec<RawBox<'_>> {
+    let mut boxes = Vec::new();
+    let mut pos = 0usize;
+    while pos + BOX_HEADER_LEN as usize <= buf.len() {
+        let compact = u32::from_be_bytes(buf[pos..pos + 4].try_into().unwrap());
+        let name: [u8; 4] = buf[pos + 4..pos + 8].try_into().unwrap();
+        let (header_len, size) = match compact {
+            0 => (BOX_HEADER_LEN as usize, buf.len() - pos),
+            1 if pos + 16 <= buf.len() => (
+                16,
+                u64::from_be_bytes(buf[pos + 8..pos + 16].try_into().unwrap()) as usize,
+            ),
+            1 => break,
+            n => (BOX_HEADER_LEN as usize, n as usize),
+        };
+        if size < header_len || pos + size > buf.len() {
+            break;
+        }
+        boxes.push(RawBox {
+            name,
+            payload: &buf[pos + header_len..pos + size],
+            bytes: &buf[pos..pos + size],
+        });
+        pos += size;
+    }
+    boxes
+}
+
+/// Writes a box whose payload is produced by `body`, patching in its size
+/// once the payload length is known.
+fn write_box(out: &mut Vec<u8>, name: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.put_u32(0);
+    out.put_slice(name);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Turns the ftyp+moov of a progressive file into the init segment of a
+/// fragmented one: sample tables are emptied and an `mvex` with one `trex`
+/// per track is appended to the moov. Boxes the rewrite does not understand
+/// are copied through unchanged.
+pub fn fragmented_init(
+    ftyp: &[u8],
+    moov: &[u8],
+    parsed: &MoovBox,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let Some(moov) = child_boxes(moov).into_iter().find(|b| &b.name == b"moov") else {
+        return Err("moov box could not be parsed".into());
+    };
+
+    let mut out = ftyp.to_vec();
+    write_box(&mut out, b"moov", |out| {
+        for child in child_boxes(moov.payload) {
+            match &child.name {
+                // Replaced by the mvex written below.
+                b"mvex" => {}
+                b"trak" => rewrite_container(out, &child),
+                _ => out.extend_from_slice(child.bytes),
+            }
+        }
+        write_box(out, b"mvex", |out| {
+            for trak in &parsed.traks {
+                out.put_u32(TREX_LEN as u32);
+                out.put_slice(b"trex");
+                out.put_u32(0);
+                out.put_u32(trak.tkhd.track_id);
+                out.put_u32(1); // default_sample_description_index
+                out.put_u32(0); // default_sample_duration
+                out.put_u32(0); // default_sample_size
+                out.put_u32(0); // default_sample_flags
+            }
+        });
+    });
+    Ok(out)
+}
+
+/// Copies a trak/mdia/minf container, descending until the `stbl` whose
+/// sample tables must be emptied.
+fn rewrite_container(out: &mut Vec<u8>, b: &RawBox<'_>) {
+    write_box(out, &b.name, |out| {
+        for child in child_boxes(b.payload) {
+            match &child.name {
+                b"mdia" | b"minf" => rewrite_container(out, &child),
+                b"stbl" => rewrite_stbl(out, &child),
+                _ => out.extend_from_slice(child.bytes),
+            }
+        }
+    });
+}
+
+fn rewrite_stbl(out: &mut Vec<u8>, stbl: &RawBox<'_>) {
+    write_box(out, b"stbl", |out| {
+        for child in child_boxes(stbl.payload) {
+            if !SAMPLE_TABLE_BOXES.contains(&&child.name) {
+                out.extend_from_slice(child.bytes);
+            }
+        }
+        // version/flags followed by a zero entry count
+        for name in [b"stts", b"stsc", b"stco"] {
+            write_box(out, name, |out| {
+                out.put_u32(0);
+                out.put_u32(0);
+            });
+        }
+        // version/flags, sample_size, sample_count
+        write_box(out, b"stsz", |out| {
+            out.put_u32(0);
+            out.put_u32(0);
+            out.put_u32(0);
+        });
+    });
+}
+
+/// Cuts the init segment of a single track out of a fragmented `init`
+/// (ftyp+moov): the moov keeps only that track's `trak` and the `trex`/`trep`
+/// entries of its `mvex`. Everything else, including other top-level boxes,
+/// is copied through so the result stays a valid init segment for one MSE
+/// SourceBuffer.
+pub fn track_init(init: &[u8], track_id: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut out = Vec::with_capacity(init.len());
+    let mut found = false;
+    for top in child_boxes(init) {
+        if &top.name != b"moov" {
+            out.extend_from_slice(top.bytes);
+            continue;
+        }
+        write_box(&mut out, b"moov", |out| {
+            for child in child_boxes(top.payload) {
+                match &child.name {
+                    b"trak" if trak_id(&child) == Some(track_id) => {
+                        found = true;
+                        out.extend_from_slice(child.bytes);
+                    }
+                    b"trak" => {}
+                    b"mvex" => write_box(out, b"mvex", |out| {
+                        for ext in child_boxes(child.payload) {
+                            let other_track = matches!(&ext.name, b"trex" | b"trep")
+                                && full_box_u32(&ext, 0) != Some(track_id);
+                            if !other_track {
+                                out.extend_from_slice(ext.bytes);
+                            }
+                        }
+                    }),
+                    _ => out.extend_from_slice(child.bytes),
+                }
+            }
+        });
+    }
+    if !found {
+        return Err(format!("init segment has no trak for track {track_id}").into());
+    }
+    Ok(out)
+}
+
+/// Sample defaults of every `trex` in the `mvex` of `moov`, a whole moov
+/// box, by track ID. mp4's `MvexBox` holds a single `trex`, which would leave
+/// all but one track of a multi-track file without its defaults.
+pub fn track_defaults(moov: &[u8]) -> HashMap<u32, TrackDefaults> {
+    child_boxes(moov)
+        .into_iter()
+        .filter(|b| &b.name == b"moov")
+        .flat_map(|moov| child_boxes(moov.payload))
+        .filter(|b| &b.name == b"mvex")
+        .flat_map(|mvex| child_boxes(mvex.payload))
+        .filter(|b| &b.name == b"trex")
+        .filter_map(|trex| {
+            // track_ID, default_sample_description_index, then the defaults
+            let defaults = TrackDefaults {
+                duration: full_box_u32(&trex, 2)?,
+                size: full_box_u32(&trex, 3)?,
+                flags: full_box_u32(&trex, 4)?,
+            };
+            Some((full_box_u32(&trex, 0)?, defaults))
+        })
+        .collect()
+}
+
+/// Track ID recorded in the `tkhd` of a `trak`.
+fn trak_id(trak: &RawBox<'_>) -> Option<u32> {
+    let tkhd = child_boxes(trak.payload)
+        .into_iter()
+        .find(|b| &b.name == b"tkhd")?;
+    // creation_time and modification_time precede track_ID and are 64-bit in
+    // version 1.
+    let skip = if *tkhd.payload.first()? == 1 { 4 } else { 2 };
+    full_box_u32(&tkhd, skip)
+}
+
+/// The `index`th 32-bit field after the version/flags of a full box.
+fn full_box_u32(b: &RawBox<'_>, index: usize) -> Option<u32> {
+    let at = 4 + index * 4;
+    let bytes = b.payload.get(at..at + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mp4::{
+        CttsBox, CttsEntry, StcoBox, StscBox, StscEntry, StssBox, StszBox, SttsBox, SttsEntry,
+        TkhdBox, TrakBox,
+    };
+    use std::io::Cursor;
+
+    fn full_box(out: &mut Vec<u8>, name: &[u8; 4], fields: &[u32]) {
+        write_box(out, name, |out| {
+            out.put_u32(0); // version and flags
+            for &field in fields {
+                out.put_u32(field);
+            }
+        });
+    }
+
+    fn trex(out: &mut Vec<u8>, track_id: u32, duration: u32) {
+        full_box(
+            out,
+            b"trex",
+            &[track_id, 1, duration, 0, NON_SYNC_SAMPLE_FLAGS],
+        );
+    }
+
+    #[test]
+    fn track_defaults_reads_every_trex() {
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"moov", |out| {
+            full_box(out, b"mvhd", &[0; 4]);
+            write_box(out, b"mvex", |out| {
+                trex(out, 1, 1001);
+                trex(out, 2, 1024);
+            });
+        });
+
+        let defaults = track_defaults(&moov);
+        assert_eq!(defaults.len(), 2);
+        assert_eq!(
+            defaults[&1],
+            TrackDefaults {
+                duration: 1001,
+                size: 0,
+                flags: NON_SYNC_SAMPLE_FLAGS,
+            }
+        );
+        assert_eq!(defaults[&2].duration, 1024);
+    }
+
+    #[test]
+    fn track_defaults_is_empty_without_mvex() {
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"moov", |out| full_box(out, b"mvhd", &[0; 4]));
+        assert!(track_defaults(&moov).is_empty());
+    }
+
+    fn trak(out: &mut Vec<u8>, track_id: u32) {
+        write_box(out, b"trak", |out| {
+            // creation_time, modification_time, track_ID
+            full_box(out, b"tkhd", &[0, 0, track_id]);
+            write_box(out, b"mdia", |_| {});
+        });
+    }
+
+    /// ftyp + moov with video track 1 and audio track 2, the latter with a
+    /// trep, followed by a free box.
+    fn two_track_init() -> Vec<u8> {
+        let mut init = Vec::new();
+        write_box(&mut init, b"ftyp", |out| out.extend_from_slice(b"isom"));
+        write_box(&mut init, b"moov", |out| {
+            full_box(out, b"mvhd", &[0; 4]);
+            trak(out, 1);
+            trak(out, 2);
+            write_box(out, b"mvex", |out| {
+                full_box(out, b"mehd", &[0]);
+                trex(out, 1, 1001);
+                trex(out, 2, 1024);
+                full_box(out, b"trep", &[2]);
+            });
+        });
+        write_box(&mut init, b"free", |_| {});
+        init
+    }
+
+    /// Paths of the boxes in `buf`, descending into moov and mvex.
+    fn layout(buf: &[u8], parent: &str) -> Vec<String> {
+        let mut paths = Vec::new();
+        for b in child_boxes(buf) {
+            let path = format!("{parent}{}", String::from_utf8_lossy(&b.name));
+            if matches!(&b.name, b"moov" | b"mvex") {
+                paths.push(path.clone());
+                paths.extend(layout(b.payload, &format!("{path}/")));
+            } else {
+                paths.push(path);
+            }
+        }
+        paths
+    }
+
+    /// Track ID of each trak, trex and trep in the moov of `init`.
+    fn track_ids(init: &[u8]) -> Vec<(String, u32)> {
+        let moov = child_boxes(init)
+            .into_iter()
+            .find(|b| &b.name == b"moov")
+            .unwrap();
+        let mut ids = Vec::new();
+        for b in child_boxes(moov.payload) {
+            match &b.name {
+                b"trak" => ids.push(("trak".to_string(), trak_id(&b).unwrap())),
+                b"mvex" => {
+                    for ext in child_boxes(b.payload)
+                        .into_iter()
+                        .filter(|e| &e.name != b"mehd")
+                    {
+                        let name = String::from_utf8_lossy(&ext.name).into_owned();
+                        ids.push((name, full_box_u32(&ext, 0).unwrap()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn track_init_keeps_only_the_track_and_its_extends() {
+        let init = two_track_init();
+
+        let video = track_init(&init, 1).unwrap();
+        assert_eq!(
+            track_ids(&video),
+            [("trak".to_string(), 1), ("trex".to_string(), 1)]
+        );
+        let audio = track_init(&init, 2).unwrap();
+        assert_eq!(
+            track_ids(&audio),
+            [
+                ("trak".to_string(), 2),
+                ("trex".to_string(), 2),
+                ("trep".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn track_init_copies_everything_else() {
+        let init = two_track_init();
+        let video = track_init(&init, 1).unwrap();
+        assert_eq!(
+            layout(&video, ""),
+            [
+                "ftyp",
+                "moov",
+                "moov/mvhd",
+                "moov/trak",
+                "moov/mvex",
+                "moov/mvex/mehd",
+                "moov/mvex/trex",
+                "free"
+            ]
+        );
+        let (before, after) = (child_boxes(&init), child_boxes(&video));
+        assert_eq!(before[0].bytes, after[0].bytes);
+        assert_eq!(before[2].bytes, after[2].bytes);
+    }
+
+    #[test]
+    fn track_init_rejects_unknown_tracks() {
+        assert!(track_init(&two_track_init(), 3).is_err());
+    }
+
+    fn stsc_entry(first_chunk: u32, samples_per_chunk: u32) -> StscEntry {
+        StscEntry {
+            first_chunk,
+            samples_per_chunk,
+            sample_description_index: 1,
+            first_sample: 0,
+        }
+    }
+
+    /// Six samples in four chunks: two per chunk in chunks 1-2 and one per
+    /// chunk from chunk 3 on, with two runs each of durations and composition
+    /// offsets and sync samples 1 and 5.
+    fn progressive_stbl() -> StblBox {
+        StblBox {
+            stts: SttsBox {
+                entries: vec![
+                    SttsEntry {
+                        sample_count: 4,
+                        sample_delta: 1000,
+                    },
+                    SttsEntry {
+                        sample_count: 2,
+                        sample_delta: 500,
+                    },
+                ],
+                ..Default::default()
+            },
+            ctts: Some(CttsBox {
+                entries: vec![
+                    CttsEntry {
+                        sample_count: 1,
+                        sample_offset: 2000,
+                    },
+                    CttsEntry {
+                        sample_count: 5,
+                        sample_offset: -500,
+                    },
+                ],
+                ..Default::default()
+            }),
+            stss: Some(StssBox {
+                entries: vec![1, 5],
+                ..Default::default()
+            }),
+            stsc: StscBox {
+                entries: vec![stsc_entry(1, 2), stsc_entry(3, 1)],
+                ..Default::default()
+            },
+            stsz: StszBox {
+                sample_count: 6,
+                sample_sizes: vec![10, 11, 12, 13, 14, 15],
+                ..Default::default()
+            },
+            stco: Some(StcoBox {
+                entries: vec![100, 200, 300, 400],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// (offset, size, duration, cts_offset, is_sync) of each sample.
+    fn fields(samples: &[Sample]) -> Vec<(u64, u32, u32, i32, bool)> {
+        samples
+            .iter()
+            .map(|s| (s.offset, s.size, s.duration, s.cts_offset, s.is_sync))
+            .collect()
+    }
+
+    #[test]
+    fn expand_samples_walks_chunks_and_runs() {
+        let samples = expand_samples(&progressive_stbl()).unwrap();
+        assert_eq!(
+            fields(&samples),
+            [
+                (100, 10, 1000, 2000, true),
+                (110, 11, 1000, -500, false),
+                (200, 12, 1000, -500, false),
+                (212, 13, 1000, -500, false),
+                (300, 14, 500, -500, true),
+                (400, 15, 500, -500, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_samples_without_stss_makes_every_sample_sync() {
+        let mut stbl = progressive_stbl();
+        stbl.stss = None;
+        stbl.stsz.sample_size = 8;
+        stbl.stsz.sample_sizes.clear();
+        let samples = expand_samples(&stbl).unwrap();
+        assert!(samples.iter().all(|s| s.is_sync && s.size == 8));
+        assert_eq!(samples[1].offset, 108);
+    }
+
+    #[test]
+    fn expand_samples_rejects_inconsistent_tables() {
+        let mut stbl = progressive_stbl();
+        stbl.stsz.sample_count = 7;
+        stbl.stsz.sample_sizes.push(16);
+        assert!(expand_samples(&stbl).is_err());
+
+        let mut stbl = progressive_stbl();
+        // Chunks 3 to 5 now hold one sample each, but there is no chunk 5.
+        stbl.stsc.entries.push(stsc_entry(6, 1));
+        assert!(expand_samples(&stbl).is_err());
+
+        let mut stbl = progressive_stbl();
+        stbl.stco = None;
+        assert!(expand_samples(&stbl).is_err());
+    }
+
+    fn be_u32(buf: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    fn find<'a>(buf: &'a [u8], name: &[u8; 4]) -> RawBox<'a> {
+        child_boxes(buf)
+            .into_iter()
+            .find(|b| &b.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn write_moof_lays_out_every_box() {
+        let samples = expand_samples(&progressive_stbl()).unwrap();
+        let mut out = Vec::new();
+        write_moof(&mut out, 2, 7, 1 << 40, 1234, &samples[..3]);
+
+        assert_eq!(out.len() as u64, moof_len(3));
+        let moof = find(&out, b"moof");
+        assert_eq!(moof.bytes.len(), out.len());
+        let mfhd = find(moof.payload, b"mfhd");
+        assert_eq!(mfhd.bytes.len() as u64, MFHD_LEN);
+        assert_eq!(be_u32(mfhd.payload, 4), 7);
+
+        let traf = find(moof.payload, b"traf");
+        let children: Vec<_> = child_boxes(traf.payload)
+            .iter()
+            .map(|b| (b.name, b.bytes.len() as u64))
+            .collect();
+        assert_eq!(
+            children,
+            [
+                (*b"tfhd", TFHD_LEN),
+                (*b"tfdt", TFDT_LEN),
+                (*b"trun", TRUN_FIXED_LEN + 3 * TRUN_SAMPLE_LEN)
+            ]
+        );
+        let tfhd = find(traf.payload, b"tfhd");
+        assert_eq!(be_u32(tfhd.payload, 0), TFHD_DEFAULT_BASE_IS_MOOF);
+        assert_eq!(be_u32(tfhd.payload, 4), 2);
+        let tfdt = find(traf.payload, b"tfdt");
+        assert_eq!(tfdt.payload[0], 1);
+        assert_eq!(&tfdt.payload[4..], (1u64 << 40).to_be_bytes());
+
+        let trun = find(traf.payload, b"trun");
+        assert_eq!(be_u32(trun.payload, 4), 3);
+        assert_eq!(be_u32(trun.payload, 8) as i32, 1234);
+        let entries: Vec<Vec<u32>> = trun.payload[12..]
+            .chunks(TRUN_SAMPLE_LEN as usize)
+            .map(|e| (0..4).map(|i| be_u32(e, i * 4)).collect())
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                vec![1000, 10, SYNC_SAMPLE_FLAGS, 2000],
+                vec![1000, 11, NON_SYNC_SAMPLE_FLAGS, -500i32 as u32],
+                vec![1000, 12, NON_SYNC_SAMPLE_FLAGS, -500i32 as u32],
+            ]
+        );
+    }
+
+    /// A progressive file where every byte holds its offset modulo 251, so
+    /// gathered samples can be told apart.
+    fn progressive_file() -> Vec<u8> {
+        (0..500).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// data_offset of the trun in a generated fragment.
+    fn data_offset(fragment: &[u8]) -> usize {
+        let moof = find(fragment, b"moof");
+        let traf = find(moof.payload, b"traf");
+        be_u32(find(traf.payload, b"trun").payload, 8) as usize
+    }
+
+    #[test]
+    fn read_synthesized_points_the_trun_at_the_mdat_payload() {
+        let file = progressive_file();
+        let samples = expand_samples(&progressive_stbl()).unwrap();
+        let frag = synthesized_frag(1, 0, 0, &samples[1..5], 1);
+        let fragment = read_synthesized(&mut Cursor::new(&file), &frag, 1, &samples[1..5]).unwrap();
+
+        assert_eq!(fragment.len() as u64, frag.byte_len());
+        let mdat = &fragment[frag.mdat_start as usize..];
+        assert_eq!(be_u32(mdat, 0) as u64, frag.mdat_size);
+        assert_eq!(&mdat[4..8], b"mdat");
+
+        let payload: Vec<u8> = samples[1..5]
+            .iter()
+            .flat_map(|s| file[s.offset as usize..(s.offset + s.size as u64) as usize].to_vec())
+            .collect();
+        let offset = data_offset(&fragment);
+        assert_eq!(offset as u64, frag.mdat_start + BOX_HEADER_LEN);
+        assert_eq!(&fragment[offset..], payload);
+    }
+
+    #[test]
+    fn read_synthesized_writes_largesize_mdat_headers() {
+        assert_eq!(
+            mdat_header_len(u32::MAX as u64 - BOX_HEADER_LEN),
+            BOX_HEADER_LEN
+        );
+        assert_eq!(mdat_header_len(u32::MAX as u64), 2 * BOX_HEADER_LEN);
+
+        // Sample data past 4 GiB cannot be built in a test, so take an
+        // ordinary fragment and give it the header the indexer picks for one.
+        let file = progressive_file();
+        let samples = expand_samples(&progressive_stbl()).unwrap();
+        let mut frag = synthesized_frag(1, 0, 0, &samples[..2], 1);
+        frag.mdat_size += BOX_HEADER_LEN;
+        let fragment = read_synthesized(&mut Cursor::new(&file), &frag, 1, &samples[..2]).unwrap();
+
+        assert_eq!(fragment.len() as u64, frag.byte_len());
+        let mdat = &fragment[frag.mdat_start as usize..];
+        assert_eq!(be_u32(mdat, 0), 1);
+        assert_eq!(&mdat[4..8], b"mdat");
+        assert_eq!(
+            u64::from_be_bytes(mdat[8..16].try_into().unwrap()),
+            frag.mdat_size
+        );
+
+        let offset = data_offset(&fragment);
+        assert_eq!(offset as u64, frag.mdat_start + 2 * BOX_HEADER_LEN);
+        assert_eq!(&fragment[offset..], &file[100..121]);
+    }
+
+    /// trak of a progressive track whose stbl holds an stsd and populated
+    /// sample tables.
+    fn progressive_trak(out: &mut Vec<u8>, track_id: u32) {
+        write_box(out, b"trak", |out| {
+            full_box(out, b"tkhd", &[0, 0, track_id]);
+            write_box(out, b"mdia", |out| {
+                full_box(out, b"mdhd", &[0, 0, 1000, 0]);
+                write_box(out, b"minf", |out| {
+                    write_box(out, b"stbl", |out| {
+                        full_box(out, b"stsd", &[0]);
+                        full_box(out, b"stts", &[1, 2, 1000]);
+                        full_box(out, b"ctts", &[1, 2, 0]);
+                        full_box(out, b"stss", &[1, 1]);
+                        full_box(out, b"stsc", &[1, 1, 2, 1]);
+                        full_box(out, b"stsz", &[0, 2, 10, 11]);
+                        full_box(out, b"stco", &[1, 100]);
+                    });
+                });
+            });
+        });
+    }
+
+    fn parsed_moov(track_ids: &[u32]) -> MoovBox {
+        MoovBox {
+            traks: track_ids
+                .iter()
+                .map(|&track_id| TrakBox {
+                    tkhd: TkhdBox {
+                        track_id,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fragmented_init_empties_sample_tables_and_adds_trex() {
+        let mut ftyp = Vec::new();
+        write_box(&mut ftyp, b"ftyp", |out| out.extend_from_slice(b"isom"));
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"moov", |out| {
+            full_box(out, b"mvhd", &[0; 4]);
+            progressive_trak(out, 1);
+            progressive_trak(out, 2);
+        });
+
+        let init = fragmented_init(&ftyp, &moov, &parsed_moov(&[1, 2])).unwrap();
+        assert_eq!(&init[..ftyp.len()], ftyp);
+        assert_eq!(
+            layout(&init, ""),
+            [
+                "ftyp",
+                "moov",
+                "moov/mvhd",
+                "moov/trak",
+                "moov/trak",
+                "moov/mvex",
+                "moov/mvex/trex",
+                "moov/mvex/trex"
+            ]
+        );
+        assert_eq!(
+            track_ids(&init),
+            [
+                ("trak".to_string(), 1),
+                ("trak".to_string(), 2),
+                ("trex".to_string(), 1),
+                ("trex".to_string(), 2)
+            ]
+        );
+
+        let moov = find(&init, b"moov");
+        for trak in child_boxes(moov.payload)
+            .iter()
+            .filter(|b| &b.name == b"trak")
+        {
+            let mdia = find(trak.payload, b"mdia");
+            assert!(child_boxes(mdia.payload).iter().any(|b| &b.name == b"mdhd"));
+            let stbl = find(find(mdia.payload, b"minf").payload, b"stbl");
+            let tables: Vec<_> = child_boxes(stbl.payload)
+                .iter()
+                .map(|b| {
+                    (
+                        String::from_utf8_lossy(&b.name).into_owned(),
+                        b.payload.to_vec(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                tables,
+                [
+                    ("stsd".to_string(), vec![0; 8]),
+                    ("stts".to_string(), vec![0; 8]),
+                    ("stsc".to_string(), vec![0; 8]),
+                    ("stco".to_string(), vec![0; 8]),
+                    ("stsz".to_string(), vec![0; 12]),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn fragmented_init_replaces_an_existing_mvex() {
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"moov", |out| {
+            progressive_trak(out, 3);
+            write_box(out, b"mvex", |out| trex(out, 9, 1001));
+        });
+        let init = fragmented_init(&[], &moov, &parsed_moov(&[3])).unwrap();
+        assert_eq!(
+            track_ids(&init),
+            [("trak".to_string(), 3), ("trex".to_string(), 3)]
+        );
+        let defaults = track_defaults(&init);
+        assert_eq!(defaults[&3], TrackDefaults::default());
+    }
+}
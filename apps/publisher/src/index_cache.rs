@@ -0,0 +1,303 @@
+// Copyright 2025 The MOQtail Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::indexer::{self, GroupingPolicy, Mp4Index};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::{info, warn};
+
+// Bump whenever the serialized shape of Mp4Index changes so that sidecars
+// written by older builds are rebuilt instead of misread.
+const SIDECAR_VERSION: u32 = 9;
+const SIDECAR_SUFFIX: &str = ".idx.json";
+
+// Hashing a multi-GB asset on every start would cost as much as re-indexing
+// it, so the content hash covers fixed-size blocks at the head, middle and
+// tail of the file. Together with size and mtime this catches replaced or
+// re-encoded media.
+const HASH_BLOCK_LEN: u64 = 1 << 20;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Fingerprint {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    version: u32,
+    fingerprint: Fingerprint,
+    grouping: GroupingPolicy,
+    index: Mp4Index,
+}
+
+fn sidecar_path(path: &str, sidecar_dir: Option<&Path>) -> PathBuf {
+    match sidecar_dir {
+        Some(dir) => {
+            let name = Path::new(path).file_name().unwrap_or_default();
+            let mut name = name.to_os_string();
+            name.push(SIDECAR_SUFFIX);
+            dir.join(name)
+        }
+        None => PathBuf::from(format!("{path}{SIDECAR_SUFFIX}")),
+    }
+}
+
+/// Returns the index for `path`, reusing its sidecar when it still matches the
+/// file and was built with the same grouping policy. The sidecar lives in
+/// `sidecar_dir` when given and next to the media file otherwise. A missing,
+/// stale or corrupt sidecar triggers a full `build_index` and the sidecar is
+/// rewritten.
+pub fn load_or_build_index(
+    path: &str,
+    grouping: GroupingPolicy,
+    sidecar_dir: Option<&Path>,
+) -> Result<Mp4Index, Box<dyn std::error::Error>> {
+    let fingerprint = fingerprint(path)?;
+    let sidecar = sidecar_path(path, sidecar_dir);
+
+    match read_sidecar(&sidecar) {
+        Ok(cached)
+            if cached.version == SIDECAR_VERSION
+                && cached.fingerprint == fingerprint
+                && cached.grouping == grouping =>
+        {
+            info!(
+                "Loaded index for {} from {} ({} fragments)",
+                path,
+                sidecar.display(),
+                cached.index.frags.len()
+            );
+            return Ok(cached.index);
+        }
+        Ok(_) => info!("Index sidecar {} is stale, rebuilding", sidecar.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!(
+            "Ignoring unreadable index sidecar {}: {}",
+            sidecar.display(),
+            e
+        ),
+    }
+
+    let index = indexer::build_index(path, grouping)?;
+    let cached = Sidecar {
+        version: SIDECAR_VERSION,
+        fingerprint,
+        grouping,
+        index,
+    };
+    // The sidecar is only an optimisation; a read-only media directory must not
+    // prevent the publisher from starting.
+    if let Err(e) = write_sidecar(&sidecar, &cached) {
+        warn!("Failed to write index sidecar {}: {}", sidecar.display(), e);
+    }
+    Ok(cached.index)
+}
+
+fn read_sidecar(sidecar: &Path) -> std::io::Result<Sidecar> {
+    let r = BufReader::new(File::open(sidecar)?);
+    Ok(serde_json::from_reader(r)?)
+}
+
+fn write_sidecar(sidecar: &Path, cached: &Sidecar) -> std::io::Result<()> {
+    // Write to a temporary file first so a crash mid-write never leaves a
+    // truncated sidecar behind that a later start would have to discard.
+    let tmp = sidecar.with_extension("json.tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut w, cached)?;
+    w.flush()?;
+    fs::rename(&tmp, sidecar)
+}
+
+fn fingerprint(path: &str) -> std::io::Result<Fingerprint> {
+    let mut f = File::open(path)?;
+    let meta = f.metadata()?;
+    let size = meta.len();
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_be_bytes());
+    let mut block = vec![0u8; HASH_BLOCK_LEN.min(size) as usize];
+    for offset in [0, size / 2, size.saturating_sub(HASH_BLOCK_LEN)] {
+        let len = HASH_BLOCK_LEN.min(size - offset) as usize;
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut block[..len])?;
+        hasher.update(&block[..len]);
+    }
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+
+    Ok(Fingerprint {
+        size,
+        mtime_secs: mtime.as_secs(),
+        mtime_nanos: mtime.subsec_nanos(),
+        hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A bare ftyp plus some payload: enough for build_index to succeed without
+    // any tracks, so the tests only exercise the caching around it.
+    const MEDIA: &[u8] = b"\x00\x00\x00\x10ftypisom\x00\x00\x02\x00\x00\x00\x00\x0cfree1234";
+    const MARKER_TRACK: u32 = 99;
+
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "publisher-index-cache-{}-{name}",
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("media.mp4"), MEDIA).unwrap();
+            Scratch(dir)
+        }
+
+        fn media(&self) -> String {
+            self.0.join("media.mp4").to_string_lossy().into_owned()
+        }
+
+        fn sidecar(&self) -> PathBuf {
+            sidecar_path(&self.media(), None)
+        }
+
+        // Tags the cached index so a later load shows whether it came from the
+        // sidecar or from a fresh build_index.
+        fn mark_sidecar(&self, edit: impl FnOnce(&mut Sidecar)) {
+            let mut cached = read_sidecar(&self.sidecar()).unwrap();
+            cached.index.timescale.insert(MARKER_TRACK, 1);
+            edit(&mut cached);
+            write_sidecar(&self.sidecar(), &cached).unwrap();
+        }
+
+        fn load(&self, grouping: GroupingPolicy) -> Mp4Index {
+            load_or_build_index(&self.media(), grouping, None).unwrap()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn from_sidecar(idx: &Mp4Index) -> bool {
+        idx.timescale.contains_key(&MARKER_TRACK)
+    }
+
+    #[test]
+    fn writes_and_reuses_the_sidecar() {
+        let scratch = Scratch::new("reuse");
+        let built = scratch.load(GroupingPolicy::default());
+        assert!(!from_sidecar(&built));
+        let cached = read_sidecar(&scratch.sidecar()).unwrap();
+        assert_eq!(cached.version, SIDECAR_VERSION);
+        assert_eq!(cached.fingerprint, fingerprint(&scratch.media()).unwrap());
+
+        scratch.mark_sidecar(|_| {});
+        assert!(from_sidecar(&scratch.load(GroupingPolicy::default())));
+    }
+
+    #[test]
+    fn changed_media_rebuilds() {
+        let grown = Scratch::new("grown");
+        grown.load(GroupingPolicy::default());
+        grown.mark_sidecar(|_| {});
+        let mut media = MEDIA.to_vec();
+        media.extend_from_slice(b"\x00\x00\x00\x08free");
+        fs::write(grown.media(), media).unwrap();
+        assert!(!from_sidecar(&grown.load(GroupingPolicy::default())));
+
+        // Same size but different bytes; pin the mtime in the sidecar so only
+        // the content hash can tell the files apart.
+        let edited = Scratch::new("edited");
+        edited.load(GroupingPolicy::default());
+        let mut media = MEDIA.to_vec();
+        *media.last_mut().unwrap() = b'5';
+        fs::write(edited.media(), media).unwrap();
+        let current = fingerprint(&edited.media()).unwrap();
+        edited.mark_sidecar(|cached| {
+            cached.fingerprint.mtime_secs = current.mtime_secs;
+            cached.fingerprint.mtime_nanos = current.mtime_nanos;
+        });
+        assert!(!from_sidecar(&edited.load(GroupingPolicy::default())));
+        // The rebuilt sidecar matches the new contents again.
+        assert_eq!(
+            read_sidecar(&edited.sidecar()).unwrap().fingerprint,
+            current
+        );
+    }
+
+    #[test]
+    fn version_or_grouping_change_rebuilds() {
+        let scratch = Scratch::new("version");
+        scratch.load(GroupingPolicy::default());
+        scratch.mark_sidecar(|cached| cached.version = SIDECAR_VERSION - 1);
+        assert!(!from_sidecar(&scratch.load(GroupingPolicy::default())));
+
+        scratch.mark_sidecar(|_| {});
+        assert!(!from_sidecar(&scratch.load(GroupingPolicy::PerGop)));
+        // The rebuild recorded the new policy, so it is reused from now on.
+        scratch.mark_sidecar(|_| {});
+        assert!(from_sidecar(&scratch.load(GroupingPolicy::PerGop)));
+    }
+
+    #[test]
+    fn unreadable_sidecar_falls_back_to_build_index() {
+        let scratch = Scratch::new("unreadable");
+        scratch.load(GroupingPolicy::default());
+        let json = fs::read(scratch.sidecar()).unwrap();
+
+        for contents in [&json[..json.len() / 2], b"not json".as_slice(), b""] {
+            fs::write(scratch.sidecar(), contents).unwrap();
+            let idx = scratch.load(GroupingPolicy::default());
+            assert!(!from_sidecar(&idx));
+            assert!(idx.frags.is_empty());
+            // A fresh, valid sidecar replaced the broken one.
+            assert_eq!(
+                read_sidecar(&scratch.sidecar()).unwrap().version,
+                SIDECAR_VERSION
+            );
+        }
+    }
+
+    #[test]
+    fn sidecar_dir_keeps_the_media_file_name() {
+        assert_eq!(
+            sidecar_path("/media/source.mp4", None),
+            PathBuf::from("/media/source.mp4.idx.json")
+        );
+        assert_eq!(
+            sidecar_path("/media/source.mp4", Some(Path::new("/var/index"))),
+            PathBuf::from("/var/index/source.mp4.idx.json")
+        );
+    }
+}
@@ -12,18 +12,83 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use mp4::{BoxHeader, BoxType, MoofBox, MoovBox, ReadBox};
+use crate::edit_list::EditList;
+use crate::fragmenter::{self, TFHD_DEFAULT_BASE_IS_MOOF};
+use mp4::{BoxType, MoofBox, MoovBox, ReadBox, TrafBox};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Seek, SeekFrom};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::str::FromStr;
+use tracing::warn;
 
-#[derive(Debug)]
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// sample_is_non_sync_sample bit of the ISO/IEC 14496-12 sample flags.
+const SAMPLE_IS_NON_SYNC: u32 = 0x0001_0000;
+
+/// How fragments are bucketed into MOQ groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupingPolicy {
+    /// Each group covers a fixed number of seconds of media, wherever sync
+    /// samples fall.
+    FixedSeconds(u64),
+    /// Groups of roughly the given number of seconds, each starting at the
+    /// first keyframe at or after the boundary.
+    SnapToGop(u64),
+    /// One group per GOP of the reference (video) track.
+    PerGop,
+}
+
+impl GroupingPolicy {
+    pub fn is_gop_aligned(self) -> bool {
+        !matches!(self, GroupingPolicy::FixedSeconds(_))
+    }
+}
+
+impl Default for GroupingPolicy {
+    fn default() -> Self {
+        GroupingPolicy::FixedSeconds(1)
+    }
+}
+
+/// Parses `fixed:<secs>`, `gop:<secs>` or `gop`.
+impl FromStr for GroupingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let secs = |v: &str| match v.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!(
+                "invalid group duration '{v}' in grouping policy '{s}'"
+            )),
+        };
+        match s.split_once(':') {
+            None if s == "gop" => Ok(GroupingPolicy::PerGop),
+            Some(("fixed", v)) => Ok(GroupingPolicy::FixedSeconds(secs(v)?)),
+            Some(("gop", v)) => Ok(GroupingPolicy::SnapToGop(secs(v)?)),
+            _ => Err(format!(
+                "unknown grouping policy '{s}', expected fixed:<secs>, gop:<secs> or gop"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct InitRange {
     pub start: u64,
     pub end: u64,
 }
 
-#[derive(Debug)]
+/// One moof+mdat pair of a single track, published as one MOQ object.
+///
+/// Every track shares the group numbering. Within a group, each MP4 track is
+/// carried in its own subgroup whose ID is the track ID (see `location`), and
+/// object IDs are unique across all tracks: they count up from 0 in
+/// presentation order, ties broken by track ID. A (group, object) pair
+/// therefore names exactly one fragment.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Frag {
     pub track_id: u32,
     pub tfdt: u64,
@@ -32,114 +97,1361 @@ pub struct Frag {
     pub moof_start: u64,
     pub mdat_start: u64,
     pub mdat_size: u64,
+    /// Whether the first sample of the fragment is a sync sample.
+    pub keyframe: bool,
+    /// The fragment's entries in `Mp4Index::samples`, if its trun (or, for
+    /// progressive inputs, its sample tables) could be read.
+    pub samples: Option<SampleRange>,
+    pub layout: FragLayout,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SampleRange {
+    pub first: usize,
+    pub count: usize,
+}
+
+/// Where the bytes of a fragment come from.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum FragLayout {
+    /// A moof+mdat pair stored contiguously in the file at `moof_start..end()`.
+    Stored,
+    /// A fragment of a progressive file. Its moof is generated from the
+    /// fragment's samples and the mdat payload is gathered from the sample
+    /// offsets, so `moof_start` and `mdat_start` are relative to the generated
+    /// moof rather than the file.
+    Synthesized { sequence_number: u32 },
+}
+
+/// A media sample, taken from the `stbl` tables of a progressive file or the
+/// trun of a fragmented one. `offset` is an absolute position in the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub offset: u64,
+    pub size: u32,
+    pub duration: u32,
+    pub cts_offset: i32,
+    pub is_sync: bool,
+}
+
+impl Frag {
+    /// Offset one past the last byte of the fragment's mdat box.
+    pub fn end(&self) -> u64 {
+        self.mdat_start + self.mdat_size
+    }
+
+    /// Length of the moof+mdat pair as stored in the file.
+    pub fn byte_len(&self) -> u64 {
+        self.end() - self.moof_start
+    }
+
+    /// Where the fragment is published. The HTTP range and fetch routes and
+    /// MOQ subscribe delivery all address fragments through this, so the same
+    /// location always carries the same payload.
+    pub fn location(&self) -> ObjectLocation {
+        ObjectLocation {
+            group: self.group,
+            subgroup: self.track_id as u64,
+            object: self.object as u64,
+        }
+    }
+}
+
+/// (group, subgroup, object) of a published fragment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ObjectLocation {
+    pub group: u64,
+    pub subgroup: u64,
+    pub object: u64,
 }
 
-#[derive(Debug)]
+/// Inclusive span of (group, object) locations.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LocationSpan {
+    pub start_group_id: u64,
+    pub start_object_id: u64,
+    pub end_group_id: u64,
+    pub end_object_id: u64,
+}
+
+impl LocationSpan {
+    pub fn new(start: (u64, u64), end: (u64, u64)) -> Self {
+        LocationSpan {
+            start_group_id: start.0,
+            start_object_id: start.1,
+            end_group_id: end.0,
+            end_object_id: end.1,
+        }
+    }
+
+    /// Span from the first to the last of `frags`.
+    pub fn of(frags: &[Frag]) -> Option<Self> {
+        let (first, last) = (frags.first()?, frags.last()?);
+        Some(LocationSpan::new(
+            (first.group, first.object as u64),
+            (last.group, last.object as u64),
+        ))
+    }
+
+    pub fn start(&self) -> (u64, u64) {
+        (self.start_group_id, self.start_object_id)
+    }
+
+    pub fn end(&self) -> (u64, u64) {
+        (self.end_group_id, self.end_object_id)
+    }
+}
+
+impl std::fmt::Display for LocationSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}:{}",
+            self.start_group_id, self.start_object_id, self.end_group_id, self.end_object_id
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Mp4Index {
     pub init: InitRange,
     pub timescale: HashMap<u32, u32>,
-    pub delay: HashMap<u32, u64>,
+    /// Edit list of every track that has one.
+    pub edits: HashMap<u32, EditList>,
     pub frags: Vec<Frag>,
+    /// Sample table referenced by `Frag::samples`, in fragment order.
+    pub samples: Vec<Sample>,
+    /// Fragmented init segment generated for progressive inputs, served in
+    /// place of the original `init` byte range.
+    pub synthesized_init: Option<Vec<u8>>,
+    /// Init segment of each track on its own (ftyp + a moov holding only that
+    /// track's trak and trex), for clients that feed every track into a
+    /// separate SourceBuffer.
+    pub track_inits: HashMap<u32, Vec<u8>>,
+    /// Indices into `frags` of each track's fragments, in order, so lookups
+    /// within one track can binary search.
+    pub track_frags: HashMap<u32, Vec<usize>>,
+}
+
+impl Mp4Index {
+    /// Returns the init segment (ftyp+moov) clients need before any fragment.
+    pub fn read_init<R: Read + Seek>(&self, r: &mut R) -> std::io::Result<Vec<u8>> {
+        match &self.synthesized_init {
+            Some(init) => Ok(init.clone()),
+            None => read_range(r, self.init.start, self.init.end),
+        }
+    }
+
+    /// Returns the init segment of a single track, if the file has it.
+    pub fn track_init(&self, track_id: u32) -> Option<&[u8]> {
+        self.track_inits.get(&track_id).map(Vec::as_slice)
+    }
+
+    /// Returns the moof+mdat bytes of `frag`, generating the moof for
+    /// fragments of progressive files.
+    pub fn read_fragment<R: Read + Seek>(
+        &self,
+        r: &mut R,
+        frag: &Frag,
+    ) -> std::io::Result<Vec<u8>> {
+        match frag.layout {
+            FragLayout::Stored => read_range(r, frag.moof_start, frag.end()),
+            FragLayout::Synthesized { sequence_number } => {
+                fragmenter::read_synthesized(r, frag, sequence_number, self.sample_slice(frag))
+            }
+        }
+    }
+
+    fn sample_slice(&self, frag: &Frag) -> &[Sample] {
+        match frag.samples {
+            Some(range) => &self.samples[range.first..range.first + range.count],
+            None => &[],
+        }
+    }
+
+    /// Iterates the samples of `frag` together with their decode times, in
+    /// the track's timescale. Yields nothing when the fragment has no sample
+    /// table.
+    pub fn frag_samples(&self, frag: &Frag) -> impl Iterator<Item = (u64, &Sample)> {
+        self.sample_slice(frag)
+            .iter()
+            .scan(frag.tfdt, |decode_time, sample| {
+                let t = *decode_time;
+                *decode_time += sample.duration as u64;
+                Some((t, sample))
+            })
+    }
+
+    /// Total sample duration of `frag`, in the track's timescale.
+    pub fn frag_duration(&self, frag: &Frag) -> Option<u64> {
+        frag.samples?;
+        Some(
+            self.frag_samples(frag)
+                .map(|(_, s)| s.duration as u64)
+                .sum(),
+        )
+    }
+
+    /// Presentation time at which the last-displayed sample of `frag` ends,
+    /// in the track's timescale and before edit lists are applied.
+    pub fn presentation_end(&self, frag: &Frag) -> Option<u64> {
+        self.frag_samples(frag)
+            .map(|(dts, s)| dts as i64 + s.cts_offset as i64 + s.duration as i64)
+            .max()
+            .map(|end| end.max(0) as u64)
+    }
+
+    /// Average bitrate of the fragment's media payload, in bits per second.
+    pub fn bitrate(&self, frag: &Frag) -> Option<u64> {
+        let duration = self.frag_duration(frag).filter(|&d| d > 0)?;
+        let ts = *self.timescale.get(&frag.track_id).unwrap_or(&1) as u128;
+        let bytes: u128 = self.frag_samples(frag).map(|(_, s)| s.size as u128).sum();
+        Some((bytes * 8 * ts / duration as u128) as u64)
+    }
+
+    /// Earliest composition time of `frag`'s samples, or its decode time when
+    /// it has no sample table, in the track's timescale.
+    pub fn composition_start(&self, frag: &Frag) -> u64 {
+        self.frag_samples(frag)
+            .map(|(dts, s)| dts.saturating_add_signed(s.cts_offset as i64))
+            .min()
+            .unwrap_or(frag.tfdt)
+    }
+
+    /// Presentation time at which `frag` starts once its track's edit list is
+    /// applied, in nanoseconds.
+    pub fn presentation_start_ns(&self, frag: &Frag) -> u64 {
+        presentation_ns(
+            frag.track_id,
+            self.composition_start(frag),
+            &self.timescale,
+            &self.edits,
+        )
+    }
+
+    /// Presentation time at which `frag` starts, in seconds.
+    pub fn start_secs(&self, frag: &Frag) -> f64 {
+        self.presentation_start_ns(frag) as f64 / NANOS_PER_SEC as f64
+    }
+
+    /// Fragment of `track_id` being presented at `seconds` on the
+    /// presentation timeline, i.e. with the track's timescale and edit-list
+    /// delay applied. `None` before the track's first fragment or after its
+    /// last one ends.
+    pub fn frag_at(&self, track_id: u32, seconds: f64) -> Option<&Frag> {
+        self.frag_index_at(track_id, secs_to_ns(seconds))
+            .map(|i| &self.frags[i])
+    }
+
+    /// Fragment published at (`group`, `object`).
+    pub fn frag_at_location(&self, group: u64, object: u64) -> Option<&Frag> {
+        let range = self.location_range((group, object), (group, object));
+        self.frags[range].first()
+    }
+
+    /// Indices into `frags` of the fragments from (group, object) `start`
+    /// through `end`, both inclusive. `frags` is sorted by location, so this
+    /// is two binary searches.
+    pub fn location_range(&self, start: (u64, u64), end: (u64, u64)) -> Range<usize> {
+        let key = |f: &Frag| (f.group, f.object as u64);
+        let lo = self.frags.partition_point(|f| key(f) < start);
+        let hi = self.frags.partition_point(|f| key(f) <= end);
+        lo..hi.max(lo)
+    }
+
+    /// Indices into `frags` of the fragments of all tracks presented between
+    /// `start` and `end` seconds: from the earliest fragment covering `start`
+    /// on any track up to the last one starting before `end`.
+    pub fn time_range(&self, start: f64, end: f64) -> Range<usize> {
+        let (start, end) = (secs_to_ns(start), secs_to_ns(end));
+        let hi = self
+            .frags
+            .partition_point(|f| self.presentation_start_ns(f) < end);
+        let lo = self
+            .timescale
+            .keys()
+            .filter_map(|&track_id| self.frag_index_at(track_id, start))
+            .min()
+            .unwrap_or_else(|| {
+                self.frags
+                    .partition_point(|f| self.presentation_start_ns(f) < start)
+            });
+        lo.min(hi)..hi
+    }
+
+    // `frags`, and with it each list in `track_frags`, is sorted by
+    // (group, object), which is also presentation order: groups are assigned
+    // by start time and objects numbered by start time within a group. That
+    // makes the partition point below valid.
+    fn frag_index_at(&self, track_id: u32, t: u64) -> Option<usize> {
+        let track = self.track_frags.get(&track_id)?;
+        let after = track.partition_point(|&i| self.presentation_start_ns(&self.frags[i]) <= t);
+        let i = *track[..after].last()?;
+        let frag = &self.frags[i];
+        let end = self
+            .presentation_end(frag)
+            .map(|end| presentation_ns(track_id, end, &self.timescale, &self.edits));
+        end.is_none_or(|end| t < end).then_some(i)
+    }
+
+    /// Offset a client adds to a track's media time to place it on the
+    /// presentation timeline, in the track's timescale. Applying it to every
+    /// track lines audio and video up.
+    pub fn presentation_offset(&self, track_id: u32) -> i64 {
+        self.edits
+            .get(&track_id)
+            .map_or(0, EditList::presentation_offset)
+    }
+
+    /// Assigns every fragment its group under `policy`. GOP-aligned policies
+    /// open groups at keyframe fragments of `reference_track`; fragments of
+    /// other tracks join the group that is open at their start time.
+    fn assign_groups(&mut self, policy: GroupingPolicy, reference_track: Option<u32>) {
+        let starts: Vec<u64> = self
+            .frags
+            .iter()
+            .map(|f| self.presentation_start_ns(f))
+            .collect();
+
+        let mut keyframes: Vec<u64> = self
+            .frags
+            .iter()
+            .zip(&starts)
+            .filter(|(f, _)| f.keyframe && Some(f.track_id) == reference_track)
+            .map(|(_, &t)| t)
+            .collect();
+        keyframes.sort_unstable();
+
+        let boundaries = match policy {
+            GroupingPolicy::FixedSeconds(_) => Vec::new(),
+            GroupingPolicy::PerGop => keyframes,
+            GroupingPolicy::SnapToGop(secs) => {
+                let period = secs * NANOS_PER_SEC;
+                let mut boundaries = Vec::new();
+                let mut next = 0;
+                for t in keyframes {
+                    if boundaries.is_empty() || t >= next {
+                        boundaries.push(t);
+                        next = (t / period + 1) * period;
+                    }
+                }
+                boundaries
+            }
+        };
+
+        for (frag, t) in self.frags.iter_mut().zip(starts) {
+            frag.group = if boundaries.is_empty() {
+                // Fixed policy, or no keyframes to align to.
+                t / bucket_ns(policy)
+            } else {
+                boundaries.partition_point(|&b| b <= t).saturating_sub(1) as u64
+            };
+        }
+    }
+
+    /// Numbers the fragments of each group across all tracks in presentation
+    /// order, ties broken by track ID, and leaves `frags` sorted by
+    /// (group, object).
+    fn number_objects(&mut self) {
+        let mut frags = std::mem::take(&mut self.frags);
+        frags.sort_by_cached_key(|f| (f.group, self.presentation_start_ns(f), f.track_id));
+
+        let mut group = None;
+        let mut next = 0;
+        for frag in &mut frags {
+            if group != Some(frag.group) {
+                group = Some(frag.group);
+                next = 0;
+            }
+            frag.object = next;
+            next += 1;
+        }
+        self.frags = frags;
+        self.index_tracks();
+    }
+
+    /// Rebuilds `track_frags` from `frags`.
+    fn index_tracks(&mut self) {
+        self.track_frags.clear();
+        for (i, frag) in self.frags.iter().enumerate() {
+            self.track_frags.entry(frag.track_id).or_default().push(i);
+        }
+    }
+}
+
+fn read_range<R: Read + Seek>(r: &mut R, start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; (end - start) as usize];
+    r.seek(SeekFrom::Start(start))?;
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn secs_to_ns(seconds: f64) -> u64 {
+    // Saturates, and maps NaN and negative times to 0.
+    (seconds * NANOS_PER_SEC as f64) as u64
+}
+
+/// Converts a track's media time to nanoseconds on the presentation
+/// timeline, applying the track's edit list.
+pub fn presentation_ns(
+    track_id: u32,
+    media_time: u64,
+    timescale: &HashMap<u32, u32>,
+    edits: &HashMap<u32, EditList>,
+) -> u64 {
+    let ts = *timescale.get(&track_id).unwrap_or(&1);
+    let t = edits
+        .get(&track_id)
+        .map_or(media_time, |e| e.to_presentation(media_time));
+    (t as u128 * NANOS_PER_SEC as u128 / ts as u128) as u64
+}
+
+/// Length of the time buckets fragments are cut and grouped by when a policy
+/// does not follow keyframes.
+pub fn bucket_ns(policy: GroupingPolicy) -> u64 {
+    match policy {
+        GroupingPolicy::FixedSeconds(secs) => secs * NANOS_PER_SEC,
+        _ => NANOS_PER_SEC,
+    }
+}
+
+/// Sample defaults a track declares in its `trex` box.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrackDefaults {
+    pub duration: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+/// Expands the trun of `traf` into samples with absolute file offsets, using
+/// `base` as the traf's data offset base.
+fn traf_samples(traf: &TrafBox, base: u64, defaults: TrackDefaults) -> Vec<Sample> {
+    let Some(trun) = &traf.trun else {
+        return Vec::new();
+    };
+    let tfhd = &traf.tfhd;
+    let duration = tfhd.default_sample_duration.unwrap_or(defaults.duration);
+    let size = tfhd.default_sample_size.unwrap_or(defaults.size);
+    let flags = tfhd.default_sample_flags.unwrap_or(defaults.flags);
+
+    let mut offset = base.saturating_add_signed(trun.data_offset.unwrap_or(0) as i64);
+    (0..trun.sample_count as usize)
+        .map(|i| {
+            let sample_flags = match trun.first_sample_flags {
+                Some(first) if i == 0 => first,
+                _ => trun.sample_flags.get(i).copied().unwrap_or(flags),
+            };
+            let sample = Sample {
+                offset,
+                size: trun.sample_sizes.get(i).copied().unwrap_or(size),
+                duration: trun.sample_durations.get(i).copied().unwrap_or(duration),
+                // Version 0 offsets are unsigned but never exceed i32::MAX in
+                // practice; version 1 stores them signed.
+                cts_offset: trun.sample_cts.get(i).map_or(0, |&c| c as i32),
+                is_sync: sample_flags & SAMPLE_IS_NON_SYNC == 0,
+            };
+            offset += sample.size as u64;
+            sample
+        })
+        .collect()
+}
+
+// Compact box header (32-bit size + fourcc) and the extended form that is
+// followed by a 64-bit largesize.
+const HEADER_LEN: u64 = 8;
+const LARGE_HEADER_LEN: u64 = 16;
+
+/// Location of a box in the file. `size` always covers the whole box, header
+/// included, regardless of whether the file used a compact, largesize or
+/// "extends to end of file" header for it.
+struct BoxSpan {
+    name: BoxType,
+    start: u64,
+    header_len: u64,
+    size: u64,
+}
+
+impl BoxSpan {
+    fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    /// Size to pass to mp4's `ReadBox`, which assumes a compact header when
+    /// working out where the box ends.
+    fn read_box_size(&self) -> u64 {
+        self.size - (self.header_len - HEADER_LEN)
+    }
+}
+
+/// Reads the box header at the current position and leaves the reader at the
+/// start of its payload. Returns `None` exactly at end of file, and an
+/// `UnexpectedEof` error when the remaining bytes cannot hold the header or
+/// the box it declares (e.g. a truncated trailing mdat).
+fn read_box_span<R: Read + Seek>(r: &mut R, file_len: u64) -> std::io::Result<Option<BoxSpan>> {
+    let start = r.stream_position()?;
+    if start >= file_len {
+        return Ok(None);
+    }
+    let truncated = |what: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("{what} at offset {start} is cut off by the end of the file at {file_len}"),
+        )
+    };
+    if start + HEADER_LEN > file_len {
+        return Err(truncated("box header".to_string()));
+    }
+
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    let compact = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let name = BoxType::from(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]));
+
+    let (header_len, size) = match compact {
+        0 => (HEADER_LEN, file_len - start),
+        1 => {
+            if start + LARGE_HEADER_LEN > file_len {
+                return Err(truncated(format!("largesize header of box {name:?}")));
+            }
+            r.read_exact(&mut buf)?;
+            (LARGE_HEADER_LEN, u64::from_be_bytes(buf))
+        }
+        n => (HEADER_LEN, n as u64),
+    };
+
+    if size < header_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("box {name:?} at offset {start} declares size {size}"),
+        ));
+    }
+    if start.checked_add(size).is_none_or(|end| end > file_len) {
+        return Err(truncated(format!("box {name:?} of {size} bytes")));
+    }
+
+    Ok(Some(BoxSpan {
+        name,
+        start,
+        header_len,
+        size,
+    }))
+}
+
+/// Like `read_box_span`, but treats a box cut off by the end of the file as
+/// the end of the file, so a recording that is still being written or was
+/// interrupted indexes up to its last complete box.
+fn next_box<R: Read + Seek>(r: &mut R, file_len: u64) -> std::io::Result<Option<BoxSpan>> {
+    match read_box_span(r, file_len) {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            warn!("Indexing stops before the end of the file: {}", e);
+            Ok(None)
+        }
+        read => read,
+    }
 }
 
-pub fn build_index(path: &str) -> Result<Mp4Index, Box<dyn std::error::Error>> {
+pub fn build_index(
+    path: &str,
+    policy: GroupingPolicy,
+) -> Result<Mp4Index, Box<dyn std::error::Error>> {
     let f = File::open(path)?;
+    let file_len = f.metadata()?.len();
     let mut r = BufReader::new(f);
 
     let mut timescale = HashMap::new();
-    let mut delay = HashMap::new();
+    let mut edits = HashMap::new();
+    let mut trex = HashMap::new();
+    let mut reference_track = None;
     let mut frags = Vec::new();
+    let mut samples = Vec::new();
 
     let mut ftyp_start = 0u64;
+    let mut ftyp_end = 0u64;
     let mut moov_start = 0u64;
-    let mut moov_size = 0u64;
-    let mut grp_counters: HashMap<u32, HashMap<u64, u32>> = HashMap::new();
+    let mut moov_end = 0u64;
+    let mut moov_box = None;
 
-    while let Ok(h) = BoxHeader::read(&mut r) {
-        let payload_pos = r.stream_position()?;
-        if h.size == 0 {
-            break;
-        }
-        let box_start = payload_pos - 8;
+    while let Some(b) = next_box(&mut r, file_len)? {
+        // Where scanning resumes; a moof arm moves it past the paired mdat.
+        let mut next_pos = b.end();
 
-        match h.name {
+        match b.name {
             BoxType::FtypBox => {
-                ftyp_start = box_start;
-                r.seek(SeekFrom::Current((h.size as i64) - 8))?;
+                ftyp_start = b.start;
+                ftyp_end = b.end();
             }
             BoxType::MoovBox => {
-                moov_start = box_start;
-                moov_size = h.size;
-                let moov = MoovBox::read_box(&mut r, h.size)?;
+                moov_start = b.start;
+                moov_end = b.end();
+                let moov = MoovBox::read_box(&mut r, b.read_box_size())?;
                 for trak in &moov.traks {
-                    timescale.insert(trak.tkhd.track_id, trak.mdia.mdhd.timescale);
+                    let media_timescale = trak.mdia.mdhd.timescale;
+                    timescale.insert(trak.tkhd.track_id, media_timescale);
                     if let Some(edts) = &trak.edts
                         && let Some(elst) = &edts.elst
-                        && elst.entries.len() == 1
+                        && !elst.entries.is_empty()
                     {
-                        delay.insert(trak.tkhd.track_id, elst.entries[0].media_time);
+                        edits.insert(
+                            trak.tkhd.track_id,
+                            EditList::from_elst(elst, moov.mvhd.timescale, media_timescale),
+                        );
                     }
                 }
+                if moov.mvex.is_some() {
+                    let raw = read_range(&mut r, b.start, b.end())?;
+                    trex.extend(fragmenter::track_defaults(&raw));
+                }
+                // GOP-aligned grouping follows the first video track, or the
+                // first track at all for audio-only files.
+                reference_track = moov
+                    .traks
+                    .iter()
+                    .find(|t| &t.mdia.hdlr.handler_type.value == b"vide")
+                    .or(moov.traks.first())
+                    .map(|t| t.tkhd.track_id);
+                moov_box = Some(moov);
             }
             BoxType::MoofBox => {
-                let moof_start = box_start;
-                let moof = MoofBox::read_box(&mut r, h.size)?;
-                let next = BoxHeader::read(&mut r)?;
+                let moof_start = b.start;
+                let moof = MoofBox::read_box(&mut r, b.read_box_size())?;
+                r.seek(SeekFrom::Start(b.end()))?;
+                let Some(next) = next_box(&mut r, file_len)? else {
+                    break;
+                };
+                next_pos = next.end();
                 if next.name != BoxType::MdatBox {
-                    r.seek(SeekFrom::Current((next.size as i64) - 8))?;
+                    r.seek(SeekFrom::Start(next_pos))?;
                     continue;
                 }
-                let mdat_payload_pos = r.stream_position()?;
-                let mdat_start = mdat_payload_pos - 8;
+                let mdat_start = next.start;
                 let mdat_size = next.size;
-                r.seek(SeekFrom::Current((next.size as i64) - 8))?;
 
-                if moof.trafs.is_empty() {
-                    continue;
-                }
+                // Without an explicit base, a traf's data follows the previous
+                // traf's data, or starts at the moof for the first one.
+                let mut data_end = moof_start;
                 for traf in &moof.trafs {
                     let track_id = traf.tfhd.track_id;
-                    if let Some(tfdt) = &traf.tfdt {
-                        let ts = *timescale.get(&track_id).unwrap_or(&1);
-                        let dly = *delay.get(&track_id).unwrap_or(&0);
-                        let adj = tfdt.base_media_decode_time.saturating_add(dly);
-                        let group = (adj as u128 / ts as u128) as u64;
-
-                        let entry = grp_counters
-                            .entry(track_id)
-                            .or_default()
-                            .entry(group)
-                            .or_insert(0);
-                        let object = *entry;
-                        *entry += 1;
+                    let base = match traf.tfhd.base_data_offset {
+                        Some(base) => base,
+                        None if traf.tfhd.flags & TFHD_DEFAULT_BASE_IS_MOOF != 0 => moof_start,
+                        None => data_end,
+                    };
+                    let defaults = trex.get(&track_id).copied().unwrap_or_default();
+                    let traf_samples = traf_samples(traf, base, defaults);
+                    if let Some(last) = traf_samples.last() {
+                        data_end = last.offset + last.size as u64;
+                    }
 
+                    if let Some(tfdt) = &traf.tfdt {
+                        let range = (!traf_samples.is_empty()).then_some(SampleRange {
+                            first: samples.len(),
+                            count: traf_samples.len(),
+                        });
                         frags.push(Frag {
                             track_id,
                             tfdt: tfdt.base_media_decode_time,
-                            group,
-                            object,
+                            group: 0,
+                            object: 0,
                             moof_start,
                             mdat_start,
                             mdat_size,
+                            keyframe: traf_samples.first().is_none_or(|s| s.is_sync),
+                            samples: range,
+                            layout: FragLayout::Stored,
                         });
+                        samples.extend(traf_samples);
                     }
                 }
             }
-            _ => {
-                r.seek(SeekFrom::Current((h.size as i64) - 8))?;
-            }
+            _ => {}
         }
+
+        r.seek(SeekFrom::Start(next_pos))?;
     }
 
-    frags.sort_by(|a, b| a.group.cmp(&b.group).then(a.object.cmp(&b.object)));
+    let mut synthesized_init = None;
+    if frags.is_empty()
+        && let Some(moov) = &moov_box
+        && moov
+            .traks
+            .iter()
+            .any(|t| t.mdia.minf.stbl.stsz.sample_count > 0)
+    {
+        // Progressive file: there are no moof boxes, so fragments are cut from
+        // the sample tables and their moof is generated when they are read.
+        (frags, samples) = fragmenter::progressive_frags(moov, &timescale, &edits, policy)?;
+        let ftyp = read_range(&mut r, ftyp_start, ftyp_end)?;
+        let moov_bytes = read_range(&mut r, moov_start, moov_end)?;
+        synthesized_init = Some(fragmenter::fragmented_init(&ftyp, &moov_bytes, moov)?);
+    }
 
-    Ok(Mp4Index {
+    let mut index = Mp4Index {
         init: InitRange {
             start: ftyp_start,
-            end: moov_start + moov_size,
+            end: moov_end,
         },
         timescale,
-        delay,
+        edits,
         frags,
-    })
+        samples,
+        synthesized_init,
+        track_inits: HashMap::new(),
+        track_frags: HashMap::new(),
+    };
+    let init = index.read_init(&mut r)?;
+    for &track_id in index.timescale.keys() {
+        // Per-track inits only serve /init/<id>; the track is still published
+        // on the combined init and media tracks without one.
+        match fragmenter::track_init(&init, track_id) {
+            Ok(track_init) => {
+                index.track_inits.insert(track_id, track_init);
+            }
+            Err(e) => warn!("No init segment of its own for track {}: {}", track_id, e),
+        }
+    }
+    index.assign_groups(policy, reference_track);
+    index.number_objects();
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mp4::{TfhdBox, TrunBox};
+    use std::io::Cursor;
+
+    #[test]
+    fn grouping_policy_parses() {
+        assert_eq!("fixed:2".parse(), Ok(GroupingPolicy::FixedSeconds(2)));
+        assert_eq!("gop:4".parse(), Ok(GroupingPolicy::SnapToGop(4)));
+        assert_eq!("gop".parse(), Ok(GroupingPolicy::PerGop));
+        for bad in [
+            "",
+            "fixed",
+            "fixed:0",
+            "fixed:-1",
+            "gop:",
+            "gop:1.5",
+            "per-gop",
+            "fixed:1:2",
+        ] {
+            assert!(bad.parse::<GroupingPolicy>().is_err(), "{bad:?} parsed");
+        }
+    }
+
+    /// Video (track 1) with keyframes at 0, 1.2, 1.5 and 2.5 s and audio
+    /// (track 2) every 0.7 s.
+    fn gop_index() -> Mp4Index {
+        let video = [
+            (0, true),
+            (500, false),
+            (1000, false),
+            (1200, true),
+            (1500, true),
+        ];
+        let video = video.into_iter().chain([(2000, false), (2500, true)]);
+        let mut frags: Vec<Frag> = video
+            .map(|(ms, keyframe)| Frag {
+                keyframe,
+                ..fixtures::frag(1, ms, 0, 0)
+            })
+            .collect();
+        frags.extend([0, 700, 1400, 2100].map(|ms| fixtures::frag(2, ms, 0, 0)));
+        fixtures::index(frags)
+    }
+
+    /// Group of each fragment of `track_id` after `assign_groups`.
+    fn groups(policy: GroupingPolicy, reference_track: Option<u32>, track_id: u32) -> Vec<u64> {
+        let mut idx = gop_index();
+        idx.assign_groups(policy, reference_track);
+        idx.frags
+            .iter()
+            .filter(|f| f.track_id == track_id)
+            .map(|f| f.group)
+            .collect()
+    }
+
+    #[test]
+    fn assign_groups_fixed_seconds() {
+        let policy = GroupingPolicy::FixedSeconds(1);
+        assert_eq!(groups(policy, Some(1), 1), [0, 0, 1, 1, 1, 2, 2]);
+        assert_eq!(groups(policy, Some(1), 2), [0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn assign_groups_per_gop() {
+        let policy = GroupingPolicy::PerGop;
+        assert_eq!(groups(policy, Some(1), 1), [0, 0, 0, 1, 2, 2, 3]);
+        // Audio joins the group open at its start time
+        assert_eq!(groups(policy, Some(1), 2), [0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn assign_groups_snap_to_gop() {
+        // The 1.5 s keyframe falls inside the group opened at 1.2 s
+        let policy = GroupingPolicy::SnapToGop(1);
+        assert_eq!(groups(policy, Some(1), 1), [0, 0, 0, 1, 1, 1, 2]);
+        assert_eq!(groups(policy, Some(1), 2), [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn assign_groups_without_keyframes_falls_back_to_seconds() {
+        assert_eq!(
+            groups(GroupingPolicy::PerGop, None, 1),
+            [0, 0, 1, 1, 1, 2, 2]
+        );
+    }
+
+    /// Video every 0.5 s from 0 and audio every 0.4 s from 0.1, one group per
+    /// second.
+    fn interleaved_index() -> Mp4Index {
+        let mut idx = fixtures::index(vec![
+            fixtures::frag(1, 1000, 0, 0),
+            fixtures::frag(2, 500, 0, 0),
+            fixtures::frag(1, 0, 0, 0),
+            fixtures::frag(2, 900, 0, 0),
+            fixtures::frag(1, 500, 0, 0),
+            fixtures::frag(2, 100, 0, 0),
+            fixtures::frag(2, 1300, 0, 0),
+        ]);
+        idx.assign_groups(GroupingPolicy::FixedSeconds(1), Some(1));
+        idx.number_objects();
+        idx
+    }
+
+    #[test]
+    fn number_objects_indexes_each_track() {
+        let idx = interleaved_index();
+        let starts = |track_id| -> Vec<u64> {
+            idx.track_frags[&track_id]
+                .iter()
+                .map(|&i| idx.frags[i].tfdt)
+                .collect()
+        };
+        assert_eq!(starts(1), [0, 500, 1000]);
+        assert_eq!(starts(2), [100, 500, 900, 1300]);
+    }
+
+    #[test]
+    fn frag_at_finds_the_fragment_being_presented() {
+        let idx = interleaved_index();
+        let start = |track_id, secs| idx.frag_at(track_id, secs).map(|f| f.tfdt);
+
+        assert_eq!(start(1, 0.0), Some(0));
+        assert_eq!(start(1, 0.7), Some(500));
+        assert_eq!(start(2, 0.7), Some(500));
+        assert_eq!(start(2, 0.9), Some(900));
+        assert_eq!(start(1, 1.2), Some(1000));
+        assert_eq!(start(2, 1.2), Some(900));
+        assert_eq!(start(2, 0.05), None);
+        assert_eq!(start(3, 1.0), None);
+    }
+
+    fn compact_box(name: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut b = ((HEADER_LEN as usize + payload.len()) as u32)
+            .to_be_bytes()
+            .to_vec();
+        b.extend_from_slice(name);
+        b.extend_from_slice(payload);
+        b
+    }
+
+    fn large_box(name: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut b = 1u32.to_be_bytes().to_vec();
+        b.extend_from_slice(name);
+        b.extend_from_slice(&(LARGE_HEADER_LEN + payload.len() as u64).to_be_bytes());
+        b.extend_from_slice(payload);
+        b
+    }
+
+    fn to_eof_box(name: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut b = 0u32.to_be_bytes().to_vec();
+        b.extend_from_slice(name);
+        b.extend_from_slice(payload);
+        b
+    }
+
+    fn read_error(file: &[u8]) -> std::io::ErrorKind {
+        let mut r = Cursor::new(file);
+        match read_box_span(&mut r, file.len() as u64) {
+            Ok(_) => panic!("read a box from a truncated file"),
+            Err(e) => e.kind(),
+        }
+    }
+
+    fn full_box(name: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+        let mut payload = (flags | (version as u32) << 24).to_be_bytes().to_vec();
+        payload.extend_from_slice(body);
+        compact_box(name, &payload)
+    }
+
+    fn words(fields: &[u32]) -> Vec<u8> {
+        fields.iter().flat_map(|f| f.to_be_bytes()).collect()
+    }
+
+    const IDENTITY: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+    const SAMPLE_DURATION: u32 = 40;
+
+    /// ftyp and moov of a fragmented file with a video track for each of
+    /// `track_ids`, all in a millisecond timescale and with samples lasting
+    /// `SAMPLE_DURATION` per their trex.
+    fn fragmented_init(track_ids: &[u32]) -> Vec<u8> {
+        let next_track_id = track_ids.iter().max().unwrap() + 1;
+        let mvhd = [
+            &[0, 0, 1000, 0, 0x0001_0000, 0x0100_0000, 0, 0][..],
+            &IDENTITY,
+            &[0; 6],
+            &[next_track_id],
+        ]
+        .concat();
+        let mut hdlr = words(&[0]);
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend(words(&[0, 0, 0]));
+        hdlr.extend_from_slice(b"VideoHandler\0");
+        let mut sample_entry = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        sample_entry.extend([0; 70]);
+        let mut stsd = words(&[1]);
+        stsd.extend(compact_box(b"mp4v", &sample_entry));
+        let mut dref = words(&[1]);
+        dref.extend(full_box(b"url ", 0, 1, &[]));
+
+        let stbl = [
+            full_box(b"stsd", 0, 0, &stsd),
+            full_box(b"stts", 0, 0, &words(&[0])),
+            full_box(b"stsc", 0, 0, &words(&[0])),
+            full_box(b"stsz", 0, 0, &words(&[0, 0])),
+            full_box(b"stco", 0, 0, &words(&[0])),
+        ]
+        .concat();
+        let minf = [
+            full_box(b"vmhd", 0, 1, &words(&[0, 0])),
+            compact_box(b"dinf", &full_box(b"dref", 0, 0, &dref)),
+            compact_box(b"stbl", &stbl),
+        ]
+        .concat();
+        let mdia = [
+            full_box(b"mdhd", 0, 0, &words(&[0, 0, 1000, 0, 0x55c4_0000])),
+            full_box(b"hdlr", 0, 0, &hdlr),
+            compact_box(b"minf", &minf),
+        ]
+        .concat();
+        let mut moov = full_box(b"mvhd", 0, 0, &words(&mvhd));
+        let mut mvex = Vec::new();
+        for &track_id in track_ids {
+            let tkhd = [
+                &[0, 0, track_id, 0, 0, 0, 0, 0, 0][..],
+                &IDENTITY,
+                &[640 << 16, 360 << 16],
+            ]
+            .concat();
+            let trak = [
+                full_box(b"tkhd", 0, 3, &words(&tkhd)),
+                compact_box(b"mdia", &mdia),
+            ]
+            .concat();
+            moov.extend(compact_box(b"trak", &trak));
+            mvex.extend(full_box(
+                b"trex",
+                0,
+                0,
+                &words(&[track_id, 1, SAMPLE_DURATION, 0, 0]),
+            ));
+        }
+        moov.extend(compact_box(b"mvex", &mvex));
+
+        let mut file = compact_box(b"ftyp", b"isom\0\0\x02\0isomiso6");
+        file.extend(compact_box(b"moov", &moov));
+        file
+    }
+
+    /// moof for samples of track 1 with the given sizes, decoded from
+    /// `tfdt`. The tfhd sets default-base-is-moof and the trun points its
+    /// data offset past an mdat header of `mdat_header_len` bytes that
+    /// directly follows the moof.
+    fn fragment_moof(
+        sequence_number: u32,
+        tfdt: u64,
+        sizes: &[u32],
+        mdat_header_len: u64,
+    ) -> Vec<u8> {
+        let moof = |data_offset: u32| {
+            let mut trun = words(&[sizes.len() as u32, data_offset]);
+            trun.extend(words(sizes));
+            let traf = [
+                full_box(b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, &words(&[1])),
+                full_box(b"tfdt", 1, 0, &tfdt.to_be_bytes()),
+                full_box(b"trun", 0, 0x201, &trun),
+            ]
+            .concat();
+            let moof = [
+                full_box(b"mfhd", 0, 0, &words(&[sequence_number])),
+                compact_box(b"traf", &traf),
+            ]
+            .concat();
+            compact_box(b"moof", &moof)
+        };
+        moof((moof(0).len() as u64 + mdat_header_len) as u32)
+    }
+
+    /// Runs `build_index` on `file`, written to a temporary path.
+    fn index_file(name: &str, file: &[u8]) -> Mp4Index {
+        let path = std::env::temp_dir().join(format!(
+            "publisher-indexer-{}-{name}.mp4",
+            std::process::id()
+        ));
+        std::fs::write(&path, file).unwrap();
+        let index = build_index(path.to_str().unwrap(), GroupingPolicy::default());
+        std::fs::remove_file(&path).unwrap();
+        index.unwrap()
+    }
+
+    #[test]
+    fn build_index_reads_largesize_and_to_eof_mdats() {
+        let init = fragmented_init(&[1]);
+        // Every sample is filled with its own byte so offsets can be checked
+        // against the payload.
+        let first_payload = [[1; 10].as_slice(), &[2; 14]].concat();
+        let second_payload = [[3; 7].as_slice(), &[4; 9], &[5; 4]].concat();
+        let first_moof = fragment_moof(1, 0, &[10, 14], LARGE_HEADER_LEN);
+        let second_moof = fragment_moof(2, 80, &[7, 9, 4], HEADER_LEN);
+
+        let mut file = init.clone();
+        file.extend(&first_moof);
+        file.extend(large_box(b"mdat", &first_payload));
+        file.extend(&second_moof);
+        file.extend(to_eof_box(b"mdat", &second_payload));
+        let idx = index_file("mdat-sizes", &file);
+
+        assert_eq!(idx.timescale, HashMap::from([(1, 1000)]));
+        assert_eq!(idx.init.end, init.len() as u64);
+        assert_eq!(idx.frags.len(), 2);
+
+        let first = &idx.frags[0];
+        let first_moof_start = init.len() as u64;
+        assert_eq!(first.moof_start, first_moof_start);
+        assert_eq!(first.mdat_start, first_moof_start + first_moof.len() as u64);
+        assert_eq!(
+            first.mdat_size,
+            LARGE_HEADER_LEN + first_payload.len() as u64
+        );
+
+        let second = &idx.frags[1];
+        assert_eq!(second.tfdt, 80);
+        assert_eq!(second.moof_start, first.end());
+        assert_eq!(
+            second.mdat_start,
+            second.moof_start + second_moof.len() as u64
+        );
+        assert_eq!(second.mdat_size, HEADER_LEN + second_payload.len() as u64);
+        assert_eq!(second.end(), file.len() as u64);
+
+        let mut r = Cursor::new(&file);
+        let mut fills = Vec::new();
+        for frag in &idx.frags {
+            let bytes = idx.read_fragment(&mut r, frag).unwrap();
+            assert_eq!(bytes, &file[frag.moof_start as usize..frag.end() as usize]);
+            for (_, s) in idx.frag_samples(frag) {
+                assert_eq!(s.duration, SAMPLE_DURATION);
+                let data = &file[s.offset as usize..(s.offset + s.size as u64) as usize];
+                assert!(data.iter().all(|&b| b == data[0]));
+                fills.push(data[0]);
+            }
+        }
+        assert_eq!(fills, [1, 2, 3, 4, 5]);
+        // The trun data offsets land on the first payload byte of each mdat.
+        let first_offsets: Vec<_> = idx.frag_samples(first).map(|(_, s)| s.offset).collect();
+        assert_eq!(
+            first_offsets,
+            [first.mdat_start + LARGE_HEADER_LEN, first.mdat_start + 26]
+        );
+        let second_offsets: Vec<_> = idx.frag_samples(second).map(|(_, s)| s.offset).collect();
+        let payload = second.mdat_start + HEADER_LEN;
+        assert_eq!(second_offsets, [payload, payload + 7, payload + 16]);
+    }
+
+    #[test]
+    fn read_box_span_rejects_truncated_boxes() {
+        let mdat = compact_box(b"mdat", &[7; 100]);
+        assert_eq!(read_error(&mdat[..50]), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_error(&mdat[..5]), std::io::ErrorKind::UnexpectedEof);
+
+        let large = large_box(b"mdat", &[7; 100]);
+        assert_eq!(read_error(&large[..12]), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_error(&large[..50]), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_box_span_rejects_sizes_smaller_than_the_header() {
+        let mut file = 4u32.to_be_bytes().to_vec();
+        file.extend_from_slice(b"free");
+        assert_eq!(read_error(&file), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn build_index_resolves_traf_data_bases() {
+        let init = fragmented_init(&[1, 2]);
+        // Track 1's data starts at its data offset from the moof; track 2 has
+        // neither a base nor a data offset, so its data follows track 1's.
+        let moof = |data_offset: u32| {
+            let first = [
+                full_box(b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, &words(&[1])),
+                full_box(b"tfdt", 1, 0, &0u64.to_be_bytes()),
+                full_box(b"trun", 0, 0x201, &words(&[2, data_offset, 5, 6])),
+            ]
+            .concat();
+            let second = [
+                full_box(b"tfhd", 0, 0, &words(&[2])),
+                full_box(b"tfdt", 1, 0, &0u64.to_be_bytes()),
+                full_box(b"trun", 0, 0x200, &words(&[1, 4])),
+            ]
+            .concat();
+            let moof = [
+                full_box(b"mfhd", 0, 0, &words(&[1])),
+                compact_box(b"traf", &first),
+                compact_box(b"traf", &second),
+            ]
+            .concat();
+            compact_box(b"moof", &moof)
+        };
+        let moof = moof((moof(0).len() as u64 + HEADER_LEN) as u32);
+        let payload = [[1; 5].as_slice(), &[2; 6], &[3; 4]].concat();
+
+        let mut file = init.clone();
+        file.extend(&moof);
+        file.extend(compact_box(b"mdat", &payload));
+        let idx = index_file("traf-bases", &file);
+
+        assert_eq!(idx.frags.len(), 2);
+        let payload_start = (init.len() + moof.len()) as u64 + HEADER_LEN;
+        let offsets = |track_id: u32| -> Vec<(u64, u32)> {
+            let frag = idx.frags.iter().find(|f| f.track_id == track_id).unwrap();
+            assert_eq!(frag.moof_start, init.len() as u64);
+            assert_eq!(frag.end(), file.len() as u64);
+            idx.frag_samples(frag)
+                .map(|(_, s)| (s.offset - payload_start, s.size))
+                .collect()
+        };
+        assert_eq!(offsets(1), [(0, 5), (5, 6)]);
+        assert_eq!(offsets(2), [(11, 4)]);
+    }
+
+    const SYNC: u32 = 0x0200_0000;
+
+    fn traf(tfhd: TfhdBox, trun: TrunBox) -> TrafBox {
+        TrafBox {
+            tfhd,
+            tfdt: None,
+            trun: Some(trun),
+        }
+    }
+
+    /// (offset, size, duration, cts_offset, is_sync) of each sample.
+    fn fields(samples: &[Sample]) -> Vec<(u64, u32, u32, i32, bool)> {
+        samples
+            .iter()
+            .map(|s| (s.offset, s.size, s.duration, s.cts_offset, s.is_sync))
+            .collect()
+    }
+
+    #[test]
+    fn traf_samples_applies_first_sample_flags_to_sample_zero_only() {
+        let tfhd = TfhdBox {
+            default_sample_duration: Some(512),
+            default_sample_size: Some(100),
+            default_sample_flags: Some(SAMPLE_IS_NON_SYNC),
+            ..Default::default()
+        };
+        let trun = TrunBox {
+            sample_count: 3,
+            data_offset: Some(16),
+            first_sample_flags: Some(SYNC),
+            ..Default::default()
+        };
+        let samples = traf_samples(&traf(tfhd, trun), 1000, TrackDefaults::default());
+        assert_eq!(
+            fields(&samples),
+            [
+                (1016, 100, 512, 0, true),
+                (1116, 100, 512, 0, false),
+                (1216, 100, 512, 0, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn traf_samples_falls_back_to_tfhd_then_trex_defaults() {
+        let trex = TrackDefaults {
+            duration: 3000,
+            size: 99,
+            flags: SAMPLE_IS_NON_SYNC,
+        };
+
+        // Per-sample sizes win over both defaults and the tfhd duration over
+        // the trex one; flags come from the trex.
+        let tfhd = TfhdBox {
+            default_sample_duration: Some(1001),
+            ..Default::default()
+        };
+        let trun = TrunBox {
+            sample_count: 2,
+            sample_sizes: vec![10, 20],
+            ..Default::default()
+        };
+        assert_eq!(
+            fields(&traf_samples(&traf(tfhd, trun), 0, trex)),
+            [(0, 10, 1001, 0, false), (10, 20, 1001, 0, false)]
+        );
+
+        // Nothing in the traf: everything comes from the trex, except flags
+        // given per sample.
+        let trun = TrunBox {
+            sample_count: 2,
+            sample_flags: vec![SYNC, SAMPLE_IS_NON_SYNC],
+            ..Default::default()
+        };
+        assert_eq!(
+            fields(&traf_samples(&traf(TfhdBox::default(), trun), 0, trex)),
+            [(0, 99, 3000, 0, true), (99, 99, 3000, 0, false)]
+        );
+    }
+
+    #[test]
+    fn traf_samples_reads_signed_composition_offsets() {
+        let trun = TrunBox {
+            version: 1,
+            sample_count: 2,
+            sample_sizes: vec![1, 1],
+            sample_cts: vec![-40i32 as u32, 80],
+            ..Default::default()
+        };
+        let samples = traf_samples(&traf(TfhdBox::default(), trun), 0, TrackDefaults::default());
+        assert_eq!(
+            samples.iter().map(|s| s.cts_offset).collect::<Vec<_>>(),
+            [-40, 80]
+        );
+    }
+
+    #[test]
+    fn traf_samples_is_empty_without_trun() {
+        let traf = TrafBox {
+            tfhd: TfhdBox::default(),
+            tfdt: None,
+            trun: None,
+        };
+        assert!(traf_samples(&traf, 0, TrackDefaults::default()).is_empty());
+    }
+
+    /// Index over one fragment of track 1 starting 1s in, holding samples of
+    /// the given (size, duration, cts_offset), in a millisecond timescale.
+    fn sampled_index(samples: &[(u32, u32, i32)]) -> Mp4Index {
+        let mut idx = fixtures::index(vec![fixtures::frag(1, 1000, 0, 0)]);
+        idx.samples = samples
+            .iter()
+            .map(|&(size, duration, cts_offset)| Sample {
+                offset: 0,
+                size,
+                duration,
+                cts_offset,
+                is_sync: true,
+            })
+            .collect();
+        idx.frags[0].samples = Some(SampleRange {
+            first: 0,
+            count: samples.len(),
+        });
+        idx
+    }
+
+    #[test]
+    fn fragment_metrics_follow_the_samples() {
+        // Presented in the order 2, 3, 1: the first sample ends last.
+        let idx = sampled_index(&[(3000, 40, 80), (1000, 40, 0), (1000, 40, 0)]);
+        let frag = &idx.frags[0];
+        assert_eq!(idx.frag_duration(frag), Some(120));
+        assert_eq!(idx.presentation_end(frag), Some(1120));
+        // 5000 bytes over 120ms
+        assert_eq!(idx.bitrate(frag), Some(333_333));
+
+        let idx = sampled_index(&[(10, 40, -20), (10, 40, -20)]);
+        assert_eq!(idx.presentation_end(&idx.frags[0]), Some(1060));
+    }
+
+    #[test]
+    fn fragment_metrics_need_samples_and_duration() {
+        let idx = fixtures::index(vec![fixtures::frag(1, 1000, 0, 0)]);
+        let frag = &idx.frags[0];
+        assert_eq!(idx.frag_duration(frag), None);
+        assert_eq!(idx.presentation_end(frag), None);
+        assert_eq!(idx.bitrate(frag), None);
+
+        let idx = sampled_index(&[(500, 0, 0)]);
+        assert_eq!(idx.frag_duration(&idx.frags[0]), Some(0));
+        assert_eq!(idx.bitrate(&idx.frags[0]), None);
+    }
+}
+
+/// Hand-built indexes over in-memory media for unit tests.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    /// Bytes of the init segment every fixture file starts with.
+    pub const INIT: &[u8] = b"ftypmoov";
+    /// Length of every fixture fragment: an 8-byte moof and an 8-byte mdat.
+    pub const FRAG_LEN: u64 = 16;
+
+    /// A stored keyframe fragment of `track_id` whose media starts `start_ms`
+    /// into the track, at (`group`, `object`).
+    pub fn frag(track_id: u32, start_ms: u64, group: u64, object: u32) -> Frag {
+        Frag {
+            track_id,
+            tfdt: start_ms,
+            group,
+            object,
+            moof_start: 0,
+            mdat_start: 0,
+            mdat_size: 0,
+            keyframe: true,
+            samples: None,
+            layout: FragLayout::Stored,
+        }
+    }
+
+    /// Index over `frags`, stored one after another behind `INIT` in the
+    /// given order, with every track in a millisecond timescale.
+    pub fn index(mut frags: Vec<Frag>) -> Mp4Index {
+        let mut timescale = HashMap::new();
+        for (i, frag) in frags.iter_mut().enumerate() {
+            frag.moof_start = INIT.len() as u64 + i as u64 * FRAG_LEN;
+            frag.mdat_start = frag.moof_start + FRAG_LEN / 2;
+            frag.mdat_size = FRAG_LEN / 2;
+            timescale.insert(frag.track_id, 1000);
+        }
+        let mut index = Mp4Index {
+            init: InitRange {
+                start: 0,
+                end: INIT.len() as u64,
+            },
+            timescale,
+            edits: HashMap::new(),
+            frags,
+            samples: Vec::new(),
+            synthesized_init: None,
+            track_inits: HashMap::new(),
+            track_frags: HashMap::new(),
+        };
+        index.index_tracks();
+        index
+    }
+
+    /// Contents of the file `index` describes: `INIT`, then each fragment
+    /// filled with its position in the file, counting from 1.
+    pub fn media(index: &Mp4Index) -> Vec<u8> {
+        let mut file = INIT.to_vec();
+        for position in 1..=index.frags.len() {
+            file.extend(std::iter::repeat_n(position as u8, FRAG_LEN as usize));
+        }
+        file
+    }
 }
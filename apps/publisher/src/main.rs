@@ -12,29 +12,77 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod edit_list;
+mod error;
+mod fragmenter;
+mod index_cache;
 mod indexer;
 mod moq_publisher_client;
 mod moqpublisher;
+mod pacing;
+mod subscriptions;
+mod supervisor;
+mod tracks;
 use std::sync::Arc;
 use warp::Filter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
     let path = std::env::args().nth(1).expect("usage: idx <file>");
-    let idx = indexer::build_index(&path)?;
-    //println!("Indexed {} fragments", idx.frags.len());
+    // fixed:<secs> (default fixed:1), gop:<secs> or gop; see GroupingPolicy
+    let grouping = match std::env::var("GROUPING_POLICY") {
+        Ok(policy) => policy.parse()?,
+        Err(_) => indexer::GroupingPolicy::default(),
+    };
+    // burst (default) or realtime[:lead=<secs>,offset=<secs>,rate=<x>]; see PacingPolicy
+    let pacing: pacing::PacingPolicy = match std::env::var("PACING") {
+        Ok(policy) => policy.parse()?,
+        Err(_) => pacing::PacingPolicy::default(),
+    };
+    // Optional directory for the index sidecar; defaults to next to the media file
+    let sidecar_dir = std::env::var_os("INDEX_SIDECAR_DIR").map(std::path::PathBuf::from);
+    let idx = index_cache::load_or_build_index(&path, grouping, sidecar_dir.as_deref())?;
+    println!("Indexed {} fragments", idx.frags.len());
+    let mut track_ids: Vec<u32> = idx.timescale.keys().copied().collect();
+    track_ids.sort_unstable();
+    for track_id in track_ids {
+        let frags = idx.frags.iter().filter(|f| f.track_id == track_id);
+        let ts = idx.timescale[&track_id] as f64;
+        let end = frags.clone().filter_map(|f| idx.presentation_end(f)).max();
+        let peak = frags.filter_map(|f| idx.bitrate(f)).max();
+        if let (Some(end), Some(peak)) = (end, peak) {
+            println!(
+                "  track {}: {:.3}s, peak fragment bitrate {} kbps",
+                track_id,
+                end as f64 / ts,
+                peak / 1000
+            );
+        }
+    }
 
     let mp4_path = Arc::new(path);
     let idx = Arc::new(idx);
+    let subscriptions = Arc::new(subscriptions::Subscriptions::default());
+    println!("Publishing with {:?} pacing", pacing);
+    let pacer = Arc::new(pacing::Pacer::new(pacing));
 
-    // Start MOQ publisher client in background
-    let mp4_path_clone = mp4_path.clone();
-    let idx_clone = idx.clone();
-    tokio::spawn(async move {
-        if let Err(e) = moq_publisher_client::run_moq_publisher(mp4_path_clone, idx_clone).await {
-            eprintln!("MOQ publisher client error: {e:?}");
-        }
-    });
+    let metrics = Arc::new(supervisor::SessionMetrics::default());
+
+    // Start MOQ publisher client in background, reconnecting whenever the relay goes away
+    tokio::spawn(supervisor::supervise(
+        mp4_path.clone(),
+        idx.clone(),
+        subscriptions.clone(),
+        pacer,
+        metrics.clone(),
+    ));
 
     let mp4_path_filter = warp::any().map({
         let mp4_path = mp4_path.clone();
@@ -53,19 +101,59 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .and(idx_filter.clone())
         .and_then(moqpublisher::handle_range_request);
 
+    let time_range_route = warp::get()
+        .and(warp::path("range"))
+        .and(warp::query::<moqpublisher::TimeRangeQuery>())
+        .and(mp4_path_filter.clone())
+        .and(idx_filter.clone())
+        .and_then(moqpublisher::handle_time_range_request);
+
+    let tracks_route = warp::get()
+        .and(warp::path("tracks"))
+        .and(idx_filter.clone())
+        .and_then(moqpublisher::handle_tracks_request);
+
+    let locate_route = warp::get()
+        .and(warp::path("locate"))
+        .and(warp::query::<moqpublisher::LocateQuery>())
+        .and(idx_filter.clone())
+        .and_then(moqpublisher::handle_locate_request);
+
+    let track_init_route = warp::get()
+        .and(warp::path!("init" / u32))
+        .and(idx_filter.clone())
+        .and_then(moqpublisher::handle_track_init_request);
+
+    let metrics_route = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::any().map(move || metrics.clone()))
+        .and_then(moqpublisher::handle_metrics_request);
+
+    let subscriptions_filter = warp::any().map(move || subscriptions.clone());
+
     let fetch_route = warp::post()
         .and(warp::path("fetch"))
         .and(warp::body::bytes())
         .and(mp4_path_filter.clone())
         .and(idx_filter.clone())
+        .and(subscriptions_filter)
         .and_then(moqpublisher::handle_fetch_request);
 
     let cors = warp::cors()
         .allow_any_origin()
         .allow_methods(vec!["GET", "POST"])
-        .allow_headers(vec!["content-type"]);
+        .allow_headers(vec!["content-type"])
+        .expose_headers(vec![moqpublisher::OBJECT_RANGE_HEADER]);
 
-    let routes = range_route.or(fetch_route).with(cors);
+    let routes = range_route
+        .or(time_range_route)
+        .or(tracks_route)
+        .or(locate_route)
+        .or(track_init_route)
+        .or(fetch_route)
+        .or(metrics_route)
+        .recover(error::handle_rejection)
+        .with(cors);
 
     println!("Server: http://localhost:8001");
     warp::serve(routes).run(([127, 0, 0, 1], 8001)).await;
@@ -12,33 +12,72 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::indexer;
+use crate::indexer::{self, ObjectLocation};
+use crate::pacing::Pacer;
+use crate::subscriptions::{self, FetchResolveError, SubscribeWindow, Subscriptions};
+use crate::supervisor::SessionMetrics;
+use crate::tracks::{
+    FetchPlan, INIT_LOCATION, INIT_TRACK, TRACK_NAMESPACE, TrackSource, fetch_object, plan_fetch,
+};
 use bytes::Bytes;
 use dotenv::dotenv;
+use moqtail::model::common::location::Location;
+use moqtail::model::common::reason_phrase::ReasonPhrase;
 use moqtail::model::control::client_setup::ClientSetup;
-use moqtail::model::control::constant;
+use moqtail::model::control::constant::{
+    self, FetchErrorCode, FilterType, GroupOrder, PublishDoneStatusCode, SubscribeErrorCode,
+};
 use moqtail::model::control::control_message::ControlMessage;
+use moqtail::model::control::fetch::Fetch;
+use moqtail::model::control::fetch_error::FetchError;
+use moqtail::model::control::fetch_ok::FetchOk;
+use moqtail::model::control::publish_done::PublishDone;
 use moqtail::model::control::publish_namespace::PublishNamespace;
+use moqtail::model::control::subscribe::Subscribe;
+use moqtail::model::control::subscribe_error::SubscribeError;
 use moqtail::model::control::subscribe_ok::SubscribeOk;
 use moqtail::model::data::constant::ObjectStatus;
+use moqtail::model::data::fetch_header::FetchHeader;
 use moqtail::model::data::subgroup_header::SubgroupHeader;
 use moqtail::model::{
     common::tuple::Tuple, data::object::Object, data::subgroup_object::SubgroupObject,
 };
 use moqtail::transport::control_stream_handler::ControlStreamHandler;
 use moqtail::transport::data_stream_handler::{HeaderInfo, SendDataStream};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::io::{Read, Seek, SeekFrom};
+use std::fs::File;
+use std::io::{Read, Seek};
 use std::sync::Arc;
-use tracing::{error, info};
-use wtransport::{ClientConfig, Endpoint};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, info, warn};
+use wtransport::{ClientConfig, Connection, Endpoint};
+
+// Objects a FETCH may read ahead of its data stream.
+const FETCH_READ_AHEAD: usize = 4;
 
 pub async fn run_moq_publisher(
     mp4_path: Arc<String>,
     idx: Arc<indexer::Mp4Index>,
+    subscriptions: Arc<Subscriptions>,
+    pacer: Arc<Pacer>,
+    metrics: Arc<SessionMetrics>,
 ) -> Result<(), anyhow::Error> {
     dotenv().ok(); // Load the .env file
-    let endpoint = env::var("RELAY_URL").expect("RELAY_URL must be set in .env");
+    let endpoint =
+        env::var("RELAY_URL").map_err(|_| anyhow::anyhow!("RELAY_URL must be set in .env"))?;
+    // Optional cap on the objects sent per track and group; unset sends them all
+    let max_objects_per_subgroup = match env::var("MAX_OBJECTS_PER_SUBGROUP") {
+        Ok(v) => {
+            let max: usize = v
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid MAX_OBJECTS_PER_SUBGROUP '{}': {}", v, e))?;
+            warn!("Publishing at most {} objects per track and group", max);
+            max
+        }
+        Err(_) => usize::MAX,
+    };
     let validate_cert = true;
     let c = ClientConfig::builder().with_bind_default();
     let config = if validate_cert {
@@ -46,14 +85,8 @@ pub async fn run_moq_publisher(
     } else {
         c.with_native_certs().build()
     };
-    let connection = Arc::new(
-        Endpoint::client(config)
-            .unwrap()
-            .connect(endpoint)
-            .await
-            .unwrap(),
-    );
-    let (send_stream, recv_stream) = connection.open_bi().await.unwrap().await.unwrap();
+    let connection = Arc::new(Endpoint::client(config)?.connect(endpoint).await?);
+    let (send_stream, recv_stream) = connection.open_bi().await?.await?;
     let mut control_stream_handler = ControlStreamHandler::new(send_stream, recv_stream);
     let client_setup = ClientSetup::new([constant::DRAFT_14].to_vec(), [].to_vec());
     match control_stream_handler.send_impl(&client_setup).await {
@@ -85,10 +118,13 @@ pub async fn run_moq_publisher(
         ));
     }
     // Announce namespace (only the namespace prefix, not the track name)
-    let my_namespace = Tuple::from_utf8_path("moqtail");
+    let my_namespace = Tuple::from_utf8_path(TRACK_NAMESPACE);
     let request_id = 0;
     let announce = PublishNamespace::new(request_id, my_namespace, &[]);
-    control_stream_handler.send_impl(&announce).await.unwrap();
+    control_stream_handler
+        .send_impl(&announce)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send announce: {:?}", e))?;
     let announce_ok = control_stream_handler.next_message().await;
     match announce_ok {
         Ok(ControlMessage::PublishNamespaceOk(_)) => {
@@ -104,21 +140,86 @@ pub async fn run_moq_publisher(
         }
     }
     info!("PublishNamespace sent successfully");
+    // Request IDs belong to the session; the relay subscribes afresh after a reconnect
+    subscriptions.clear_requests();
+    metrics.session_established();
 
-    // Keep track of which aliases we've started publishing for so we don't spawn duplicate tasks
-    let mut published_aliases: std::collections::HashSet<u64> = std::collections::HashSet::new();
+    // Every subscription gets its own alias, even on a track that is already being published
+    let mut next_track_alias: u64 = 1;
+    // Fetches still being served, by request ID, so FETCH_CANCEL can stop them
+    let mut fetch_tasks: HashMap<u64, watch::Sender<bool>> = HashMap::new();
+    // Subscriptions still being published, by request ID, with the number of
+    // data streams opened for each so far
+    let mut publications: HashMap<u64, (watch::Sender<bool>, Arc<AtomicU64>)> = HashMap::new();
+    // Publishing tasks report here when they end, so PUBLISH_DONE goes out on
+    // the control stream
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel::<(u64, PublishEnd)>();
 
     // Listen for control messages and respond to SUBSCRIBE by sending SubscribeOk
-    loop {
-        let msg = control_stream_handler.next_message().await;
+    let failure = loop {
+        // next_message keeps partial reads in the handler's buffer, so it is
+        // safe to drop when a publishing task ends first
+        let msg = tokio::select! {
+            msg = control_stream_handler.next_message() => msg,
+            Some((request_id, end)) = done_rx.recv() => {
+                // A subscription that ran to its end stays joinable until the
+                // relay unsubscribes
+                if let Some((cancel, streams)) = publications.remove(&request_id) {
+                    // Sent only now, so the stream count covers every stream
+                    // the task opened before it saw the UNSUBSCRIBE
+                    let end = if *cancel.borrow() {
+                        PublishEnd {
+                            status: PublishDoneStatusCode::SubscriptionEnded,
+                            reason: "Unsubscribed".to_string(),
+                        }
+                    } else {
+                        end
+                    };
+                    send_publish_done(
+                        &mut control_stream_handler,
+                        request_id,
+                        streams.load(Ordering::Relaxed),
+                        end,
+                    )
+                    .await;
+                }
+                continue;
+            }
+        };
         match msg {
             Ok(ControlMessage::Subscribe(s)) => {
                 // s is a Box<Subscribe>
                 info!("Received Subscribe message: {:?}", s);
                 let sub = *s;
 
-                // choose a track alias for publishing; 1 is fine for a single published track
-                let track_alias: u64 = 1;
+                let (source, window) = match accept_subscribe(&sub, &idx, &pacer) {
+                    Ok(accepted) => accepted,
+                    Err((code, reason)) => {
+                        error!("Rejecting Subscribe {}: {}", sub.request_id, reason);
+                        let subscribe_error = match ReasonPhrase::try_new(reason) {
+                            Ok(reason) => SubscribeError::new(sub.request_id, code, reason),
+                            Err(e) => {
+                                error!("Failed to build SubscribeError reason: {:?}", e);
+                                continue;
+                            }
+                        };
+                        if let Err(e) = control_stream_handler.send_impl(&subscribe_error).await {
+                            error!("Failed to send SubscribeError: {:?}", e);
+                        }
+                        continue;
+                    }
+                };
+                info!(
+                    "Subscribe {} on {} starts at {}:{}, ends after group {:?}",
+                    sub.request_id,
+                    sub.track_name,
+                    window.start.0,
+                    window.start.1,
+                    window.end_group
+                );
+
+                let track_alias = next_track_alias;
+                next_track_alias += 1;
                 let expires: u64 = 0;
 
                 // send SubscribeOk back to relay so it can map alias -> full track name
@@ -126,7 +227,9 @@ pub async fn run_moq_publisher(
                     sub.request_id,
                     track_alias,
                     expires,
-                    None,
+                    window
+                        .largest
+                        .map(|(group, object)| Location::new(group, object)),
                     None,
                 );
 
@@ -136,324 +239,1413 @@ pub async fn run_moq_publisher(
                 }
 
                 info!(
-                    "SubscribeOk sent for request {} with alias {}",
-                    sub.request_id, track_alias
+                    "SubscribeOk sent for request {} with alias {} ({:?})",
+                    sub.request_id, track_alias, source
                 );
-
-                // Spawn the proactive publishing task now that alias is registered
-                // Avoid spawning multiple publisher tasks for the same alias
-                if published_aliases.contains(&track_alias) {
-                    info!(
-                        "Already publishing for alias {}, skipping spawn",
-                        track_alias
-                    );
-                    continue;
-                }
-                published_aliases.insert(track_alias);
+                subscriptions.insert(sub.request_id, sub.track_name.clone(), window.largest);
 
                 let conn_clone = connection.clone();
                 let mp4_path_clone = mp4_path.clone();
                 let idx_clone = idx.clone();
+                let pacer_clone = pacer.clone();
+                let request_id = sub.request_id;
+                let streams = Arc::new(AtomicU64::new(0));
+                let (cancel_tx, cancel) = watch::channel(false);
+                let publication = Publication {
+                    track_name: sub.track_name,
+                    track_alias,
+                    source,
+                    window,
+                    streams: streams.clone(),
+                    cancel,
+                };
+                let done_tx = done_tx.clone();
+                // SUBSCRIBE_OK has told the relay the alias, so objects sent
+                // from here on can be matched to the subscription
                 tokio::spawn(async move {
-                    // publisher priority
-                    let publisher_priority: u8 = 128;
-
-                    // open the file once
-                    let mut file = match std::fs::File::open(&*mp4_path_clone) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            error!("Failed to open mp4 file for publishing: {:?}", e);
-                            return;
+                    let end = match source {
+                        TrackSource::Init => {
+                            publish_init(conn_clone, publication, mp4_path_clone, idx_clone).await
+                        }
+                        _ => {
+                            publish_media(
+                                conn_clone,
+                                publication,
+                                mp4_path_clone,
+                                idx_clone,
+                                pacer_clone,
+                                max_objects_per_subgroup,
+                            )
+                            .await
                         }
                     };
-
-                    // group fragments by group id
-                    let mut groups: std::collections::BTreeMap<u64, Vec<_>> =
-                        std::collections::BTreeMap::new();
-                    for frag in &idx_clone.frags {
-                        groups.entry(frag.group).or_default().push(frag);
+                    let _ = done_tx.send((request_id, end));
+                });
+                publications.insert(request_id, (cancel_tx, streams));
+            }
+            Ok(ControlMessage::Unsubscribe(unsub)) => {
+                info!("Received Unsubscribe message: {:?}", unsub);
+                // Joining FETCHes may no longer name the subscription, even one
+                // whose publishing already ended
+                subscriptions.remove(unsub.request_id);
+                // PUBLISH_DONE follows once the task has stopped
+                match publications.get(&unsub.request_id) {
+                    Some((cancel, _)) => {
+                        let _ = cancel.send(true);
                     }
+                    None => info!(
+                        "Unsubscribe for subscription {} that is not being published",
+                        unsub.request_id
+                    ),
+                }
+            }
+            Ok(ControlMessage::Fetch(fetch)) => {
+                info!("Received Fetch message: {:?}", fetch);
+                // A finished task has dropped its end of the channel
+                fetch_tasks.retain(|_, cancel| !cancel.is_closed());
+                let request_id = fetch.request_id;
 
-                    // Send init segment (ftyp+moov) as group 0 object 0 so subscribers and caches
-                    // receive the MP4 initialization segment before any media fragments.
-                    // This mirrors the behavior of the HTTP/Fetch handlers which include the init
-                    // segment first.
-                    let init_len = (idx_clone.init.end - idx_clone.init.start) as usize;
-                    if init_len > 0 {
-                        let mut init_buf = vec![0u8; init_len];
-                        if let Err(e) = file.seek(SeekFrom::Start(idx_clone.init.start)) {
-                            error!("Failed to seek to init start: {:?}", e);
-                        } else if let Err(e) = file.read_exact(&mut init_buf) {
-                            error!("Failed to read init bytes: {:?}", e);
-                        } else {
-                            // open a unidirectional stream for the init segment
-                            let stream_res = conn_clone.open_uni().await;
-                            match stream_res {
-                                Err(e) => {
-                                    error!("Failed to open uni stream for init: {:?}", e);
-                                }
-                                Ok(pending) => {
-                                    match pending.await {
-                                        Err(e) => error!(
-                                            "Failed to complete open uni stream for init: {:?}",
-                                            e
-                                        ),
-                                        Ok(send_stream) => {
-                                            let send_stream =
-                                                Arc::new(tokio::sync::Mutex::new(send_stream));
-
-                                            // For the init segment we will publish it as group 0 object 0
-                                            // Use explicit subgroup id 0 so receivers know this is the init object
-                                            let sub_header = SubgroupHeader::new_with_explicit_id(
-                                                track_alias,
-                                                0,
-                                                0,
-                                                publisher_priority,
-                                                true, // if false then extension headers must be set to None
-                                                true,
-                                            );
-
-                                            let header_info =
-                                                HeaderInfo::Subgroup { header: sub_header };
-                                            match SendDataStream::new(
-                                                send_stream.clone(),
-                                                header_info,
-                                            )
-                                            .await
-                                            {
-                                                Ok(mut stream_handler) => {
-                                                    let subgroup_obj = SubgroupObject {
-                                                        object_id: 0,
-                                                        extension_headers: Some(vec![]),
-                                                        object_status: Some(ObjectStatus::Normal),
-                                                        payload: Some(Bytes::from(
-                                                            init_buf.clone(),
-                                                        )),
-                                                    };
-
-                                                    let object = match Object::try_from_subgroup(
-                                                        subgroup_obj,
-                                                        track_alias,
-                                                        0,
-                                                        Some(0),
-                                                        publisher_priority,
-                                                    ) {
-                                                        Ok(o) => o,
-                                                        Err(e) => {
-                                                            error!(
-                                                                "Failed to build init Object from subgroup: {:?}",
-                                                                e
-                                                            );
-                                                            // Not inside loop here — skip sending init object.
-                                                            // Return early from the spawned task to avoid further errors.
-                                                            return;
-                                                        }
-                                                    };
-
-                                                    if let Err(e) = stream_handler
-                                                        .send_object(&object, None)
-                                                        .await
-                                                    {
-                                                        error!(
-                                                            "Failed to send init object: {:?}",
-                                                            e
-                                                        );
-                                                    }
-
-                                                    if let Err(e) = stream_handler.flush().await {
-                                                        error!(
-                                                            "Failed to flush init stream: {:?}",
-                                                            e
-                                                        );
-                                                    }
-
-                                                    if let Err(e) = stream_handler.finish().await {
-                                                        error!(
-                                                            "Failed to finish init stream: {:?}",
-                                                            e
-                                                        );
-                                                    }
-                                                    info!(
-                                                        "Sent init segment as group 0 object 0 ({} bytes)",
-                                                        init_len
-                                                    );
-                                                }
-                                                Err(e) => error!(
-                                                    "Failed to create SendDataStream for init: {:?}",
-                                                    e
-                                                ),
-                                            }
-                                        }
-                                    }
-                                }
+                let (plan, largest) = match accept_fetch(&fetch, &idx, &subscriptions) {
+                    Ok(accepted) => accepted,
+                    Err((code, reason)) => {
+                        error!("Rejecting Fetch {}: {}", request_id, reason);
+                        let fetch_error = match ReasonPhrase::try_new(reason) {
+                            Ok(reason) => FetchError::new(request_id, code, reason),
+                            Err(e) => {
+                                error!("Failed to build FetchError reason: {:?}", e);
+                                continue;
                             }
+                        };
+                        if let Err(e) = control_stream_handler.send_impl(&fetch_error).await {
+                            error!("Failed to send FetchError: {:?}", e);
                         }
+                        continue;
                     }
+                };
 
-                    for (group_id, frags) in groups {
-                        info!(
-                            "Publishing group {} with {} fragments (total across tracks)",
-                            group_id,
-                            frags.len()
-                        );
-                        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-                        // Partition the fragments for this group by track id so we publish one
-                        // unidirectional stream per track (video/audio), which ensures both
-                        // tracks' objects are sent (previously only one stream per group was used).
-                        let mut per_track: std::collections::BTreeMap<u32, Vec<_>> =
-                            std::collections::BTreeMap::new();
-                        for frag in frags.iter() {
-                            per_track
-                                .entry(frag.track_id)
-                                .or_default()
-                                .push(frag.clone());
-                        }
-
-                        for (track_id, track_frags) in per_track.into_iter() {
-                            info!(
-                                "Publishing group {} track {} with {} fragments",
-                                group_id,
-                                track_id,
-                                track_frags.len()
-                            );
-
-                            // open a unidirectional stream for this track
-                            let stream_res = conn_clone.open_uni().await;
-                            if let Err(e) = stream_res {
-                                error!(
-                                    "Failed to open uni stream for group {} track {}: {:?}",
-                                    group_id, track_id, e
-                                );
-                                continue;
-                            }
-                            let pending = stream_res.unwrap();
-                            let open_res = pending.await;
-                            if let Err(e) = open_res {
-                                error!(
-                                    "Failed to complete open uni stream for group {} track {}: {:?}",
-                                    group_id, track_id, e
-                                );
-                                continue;
-                            }
-                            let send_stream = open_res.unwrap();
-                            let send_stream = Arc::new(tokio::sync::Mutex::new(send_stream));
-
-                            let subgroup_id: u64 = 1;
-
-                            let sub_header = SubgroupHeader::new_with_explicit_id(
-                                track_alias,
-                                group_id,
-                                subgroup_id,
-                                publisher_priority,
-                                true,
-                                true,
-                            );
-
-                            let header_info = HeaderInfo::Subgroup { header: sub_header };
-                            let mut stream_handler = match SendDataStream::new(
-                                send_stream.clone(),
-                                header_info,
-                            )
-                            .await
-                            {
-                                Ok(s) => s,
-                                Err(e) => {
-                                    error!(
-                                        "Failed to create SendDataStream for group {} track {}: {:?}",
-                                        group_id, track_id, e
-                                    );
-                                    continue;
-                                }
-                            };
-
-                            // send each fragment for this track
-                            let mut prev_object_id: Option<u64> = None;
-                            for (i, frag) in track_frags.iter().enumerate().take(24) {
-                                let object_id_for_frag: u64 = (i as u64) + 1;
-                                let moof_size = frag.mdat_start - frag.moof_start;
-                                let total_size = moof_size + frag.mdat_size;
-                                let mut buf = vec![0u8; total_size as usize];
-                                if let Err(e) = file.seek(SeekFrom::Start(frag.moof_start)) {
-                                    error!("Failed to seek mp4 file: {:?}", e);
-                                    break;
-                                }
-                                if let Err(e) = file.read_exact(&mut buf) {
-                                    error!("Failed to read fragment bytes: {:?}", e);
-                                    break;
-                                }
-
-                                let subgroup_obj = SubgroupObject {
-                                    object_id: object_id_for_frag,
-                                    extension_headers: Some(vec![]),
-                                    object_status: Some(ObjectStatus::Normal),
-                                    payload: Some(Bytes::from(buf)),
-                                };
-
-                                let object = match Object::try_from_subgroup(
-                                    subgroup_obj,
-                                    track_alias,
-                                    group_id,
-                                    Some(subgroup_id),
-                                    publisher_priority,
-                                ) {
-                                    Ok(o) => o,
-                                    Err(e) => {
-                                        error!("Failed to build Object from subgroup: {:?}", e);
-                                        // skip this object
-                                        continue;
-                                    }
-                                };
-
-                                if let Err(e) =
-                                    stream_handler.send_object(&object, prev_object_id).await
-                                {
-                                    error!(
-                                        "Failed to send object for group {} track {} object {}: {:?}",
-                                        group_id, track_id, object_id_for_frag, e
-                                    );
-                                    break;
-                                } else {
-                                    info!(
-                                        "Sent object for group {} track {} object {} (size={})",
-                                        group_id,
-                                        track_id,
-                                        object_id_for_frag,
-                                        object.payload.as_ref().map(|p| p.len()).unwrap_or(0)
-                                    );
-                                }
-                                prev_object_id = Some(object_id_for_frag);
-                            }
-
-                            if let Err(e) = stream_handler.flush().await {
-                                error!(
-                                    "Failed to flush stream for group {} track {}: {:?}",
-                                    group_id, track_id, e
-                                );
-                            }
-                            if let Err(e) = stream_handler.finish().await {
-                                error!(
-                                    "Failed to finish stream for group {} track {}: {:?}",
-                                    group_id, track_id, e
-                                );
-                            }
-                        }
+                let end_location = Location::new(largest.group, largest.object);
+                let sent = if fetch.group_order == GroupOrder::Descending {
+                    let fetch_ok =
+                        FetchOk::new_descending(request_id, plan.end_of_track, end_location, None);
+                    control_stream_handler.send_impl(&fetch_ok).await
+                } else {
+                    let fetch_ok =
+                        FetchOk::new_ascending(request_id, plan.end_of_track, end_location, None);
+                    control_stream_handler.send_impl(&fetch_ok).await
+                };
+                if let Err(e) = sent {
+                    error!("Failed to send FetchOk: {:?}", e);
+                    continue;
+                }
+                info!(
+                    "FetchOk sent for request {} with {} objects up to {}:{}",
+                    request_id,
+                    plan.objects.len(),
+                    largest.group,
+                    largest.object
+                );
 
-                        info!("Finished publishing group {}", group_id);
-                    }
-                });
-            }
-            Ok(ControlMessage::Fetch(fetch)) => {
-                info!("Received Fetch message: {:?}", fetch);
-                // TODO: respond to fetchs via control stream or data streams if desired
+                let (cancel_tx, cancel) = watch::channel(false);
+                tokio::spawn(serve_fetch(
+                    connection.clone(),
+                    request_id,
+                    plan,
+                    mp4_path.clone(),
+                    idx.clone(),
+                    cancel,
+                ));
+                fetch_tasks.insert(request_id, cancel_tx);
             }
+            Ok(ControlMessage::FetchCancel(cancel)) => match fetch_tasks.remove(&cancel.request_id)
+            {
+                Some(task) => {
+                    let _ = task.send(true);
+                    info!("Cancelled Fetch {}", cancel.request_id);
+                }
+                None => info!(
+                    "FetchCancel for Fetch {} that is not being served",
+                    cancel.request_id
+                ),
+            },
             Ok(other) => {
                 info!("Received other control message: {:?}", other);
             }
             Err(e) => {
                 error!("Error receiving control message: {:?}", e);
+                break anyhow::anyhow!("Control stream failed: {:?}", e);
+            }
+        }
+    };
+
+    // Nothing these tasks send can reach the relay any more
+    for (cancel, _) in publications.values() {
+        let _ = cancel.send(true);
+    }
+    for cancel in fetch_tasks.values() {
+        let _ = cancel.send(true);
+    }
+    Err(failure)
+}
+
+/// Resolves `sub` to the track it names and the window it asks for, or to the
+/// SUBSCRIBE_ERROR code and reason to reject it with.
+fn accept_subscribe(
+    sub: &Subscribe,
+    idx: &indexer::Mp4Index,
+    pacer: &Pacer,
+) -> Result<(TrackSource, SubscribeWindow), (SubscribeErrorCode, String)> {
+    let source = TrackSource::for_full_name(idx, &sub.track_namespace, &sub.track_name)
+        .ok_or_else(|| {
+            (
+                SubscribeErrorCode::TrackDoesNotExist,
+                format!(
+                    "No track {} under {}",
+                    sub.track_name,
+                    sub.track_namespace.to_utf8_path()
+                ),
+            )
+        })?;
+    let window = subscriptions::resolve_subscribe(sub, pacer.live_location(idx, source))
+        .map_err(|reason| (SubscribeErrorCode::InvalidRange, reason))?;
+
+    // Relative filters may point past the end once everything is out, but an
+    // absolute start there would wait for objects that never come.
+    let absolute = matches!(
+        sub.filter_type,
+        FilterType::AbsoluteStart | FilterType::AbsoluteRange
+    );
+    if let Some(last) = source.last_location(idx)
+        && absolute
+        && window.start > last
+    {
+        return Err((
+            SubscribeErrorCode::InvalidRange,
+            format!(
+                "Subscription starts at {}:{}, past the last object {}:{} of {}",
+                window.start.0, window.start.1, last.0, last.1, sub.track_name
+            ),
+        ));
+    }
+    Ok((source, window))
+}
+
+/// Resolves `fetch` to the objects it returns and the largest location among
+/// them, or to the FETCH_ERROR code and reason to reject it with.
+fn accept_fetch(
+    fetch: &Fetch,
+    idx: &indexer::Mp4Index,
+    subscriptions: &Subscriptions,
+) -> Result<(FetchPlan, ObjectLocation), (FetchErrorCode, String)> {
+    let target = subscriptions.resolve_fetch(fetch).map_err(|e| {
+        let code = match e {
+            FetchResolveError::Malformed(_) => FetchErrorCode::InvalidRange,
+            FetchResolveError::UnknownSubscription(_) => FetchErrorCode::InvalidJoiningRequestId,
+            FetchResolveError::NoObjects(_) => FetchErrorCode::NoObjects,
+        };
+        (code, e.to_string())
+    })?;
+    let source = TrackSource::for_fetch(idx, fetch, &target).ok_or_else(|| {
+        (
+            FetchErrorCode::TrackDoesNotExist,
+            format!("No track {} under {}", target.track_name, TRACK_NAMESPACE),
+        )
+    })?;
+    let span = format!(
+        "{} {}:{} → {}:{}",
+        target.track_name, target.start.0, target.start.1, target.end.0, target.end.1
+    );
+    if target.start > target.end {
+        return Err((
+            FetchErrorCode::InvalidRange,
+            format!("Fetch range {} ends before it starts", span),
+        ));
+    }
+
+    let plan = plan_fetch(
+        idx,
+        source,
+        &target,
+        fetch.group_order == GroupOrder::Descending,
+    );
+    match plan.largest(idx) {
+        Some(largest) => Ok((plan, largest)),
+        None => Err((
+            FetchErrorCode::NoObjects,
+            format!("No objects in fetch range {}", span),
+        )),
+    }
+}
+
+/// Writes an accepted FETCH on its own data stream: the FETCH_HEADER, then
+/// each object of `plan` in order. Once `cancel` is set, by FETCH_CANCEL, the
+/// stream is finished after the object being sent.
+async fn serve_fetch(
+    connection: Arc<Connection>,
+    request_id: u64,
+    plan: FetchPlan,
+    mp4_path: Arc<String>,
+    idx: Arc<indexer::Mp4Index>,
+    mut cancel: Cancel,
+) {
+    // Opened off the runtime, like every read of the file that follows
+    let file = match tokio::fs::File::open(&*mp4_path).await {
+        Ok(f) => f.into_std().await,
+        Err(e) => {
+            error!("Failed to open mp4 file for Fetch {}: {:?}", request_id, e);
+            return;
+        }
+    };
+    let send_stream = match connection.open_uni().await {
+        Ok(pending) => match pending.await {
+            Ok(s) => s,
+            Err(e) => {
+                error!(
+                    "Failed to complete open uni stream for Fetch {}: {:?}",
+                    request_id, e
+                );
+                return;
+            }
+        },
+        Err(e) => {
+            error!(
+                "Failed to open uni stream for Fetch {}: {:?}",
+                request_id, e
+            );
+            return;
+        }
+    };
+    let send_stream = Arc::new(tokio::sync::Mutex::new(send_stream));
+
+    let header_info = HeaderInfo::Fetch {
+        header: FetchHeader::new(request_id),
+    };
+    let mut stream_handler = match SendDataStream::new(send_stream, header_info).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!(
+                "Failed to create SendDataStream for Fetch {}: {:?}",
+                request_id, e
+            );
+            return;
+        }
+    };
+
+    let total = plan.objects.len();
+    let mut sent = 0;
+    let mut objects = read_fetch_objects(plan, idx, file);
+    loop {
+        let (loc, read) = tokio::select! {
+            next = objects.recv() => match next {
+                Some(next) => next,
+                None => break,
+            },
+            _ = until_cancelled(&mut cancel) => break,
+        };
+        let payload = match read {
+            Ok(buf) => buf,
+            Err(e) => {
+                error!(
+                    "Failed to read object {:?} for Fetch {}: {:?}",
+                    loc, request_id, e
+                );
+                break;
+            }
+        };
+        // Fetch streams name their track through the request ID, not an alias
+        let object = match Object::try_from_fetch(fetch_object(loc, payload), 0) {
+            Ok(o) => o,
+            Err(e) => {
+                error!("Failed to build Object at {:?} from fetch: {:?}", loc, e);
+                break;
+            }
+        };
+        // FetchObjects carry their full location, so there is no previous
+        // object ID to encode against
+        if let Err(e) = stream_handler.send_object(&object, None).await {
+            error!(
+                "Failed to send object {:?} for Fetch {}: {:?}",
+                loc, request_id, e
+            );
+            break;
+        }
+        sent += 1;
+    }
+
+    if let Err(e) = stream_handler.flush().await {
+        error!("Failed to flush stream for Fetch {}: {:?}", request_id, e);
+    }
+    if let Err(e) = stream_handler.finish().await {
+        error!("Failed to finish stream for Fetch {}: {:?}", request_id, e);
+    }
+    info!(
+        "Served Fetch {} with {} of {} objects",
+        request_id, sent, total
+    );
+}
+
+/// Reads the objects of `plan` on the blocking pool, at most
+/// `FETCH_READ_AHEAD` ahead of the stream sending them. Reading stops after
+/// the first failure or once the receiver is dropped.
+fn read_fetch_objects(
+    plan: FetchPlan,
+    idx: Arc<indexer::Mp4Index>,
+    mut file: File,
+) -> mpsc::Receiver<(ObjectLocation, std::io::Result<Vec<u8>>)> {
+    let (tx, rx) = mpsc::channel(FETCH_READ_AHEAD);
+    tokio::task::spawn_blocking(move || {
+        for item in plan.objects {
+            let read = item.read(&idx, &mut file);
+            let failed = read.is_err();
+            if tx.blocking_send((item.location(&idx), read)).is_err() || failed {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// An accepted subscription, as its publishing task serves it.
+struct Publication {
+    track_name: String,
+    track_alias: u64,
+    source: TrackSource,
+    window: SubscribeWindow,
+    /// Data streams opened so far, for PUBLISH_DONE.
+    streams: Arc<AtomicU64>,
+    cancel: Cancel,
+}
+
+/// Set once the relay no longer wants a task's objects: on UNSUBSCRIBE,
+/// FETCH_CANCEL or the end of the session. Tasks then finish the data stream
+/// they have open and stop, rather than being aborted with it left open.
+type Cancel = watch::Receiver<bool>;
+
+/// Completes once `cancel` is set, or its sender is gone.
+async fn until_cancelled(cancel: &mut Cancel) {
+    let _ = cancel.wait_for(|&cancelled| cancelled).await;
+}
+
+/// How a publishing task ended, as its PUBLISH_DONE reports it.
+struct PublishEnd {
+    status: PublishDoneStatusCode,
+    reason: String,
+}
+
+impl PublishEnd {
+    fn failed(reason: String) -> Self {
+        PublishEnd {
+            status: PublishDoneStatusCode::InternalError,
+            reason,
+        }
+    }
+}
+
+/// Tells the relay that subscription `request_id` will get no more objects.
+async fn send_publish_done(
+    control_stream_handler: &mut ControlStreamHandler,
+    request_id: u64,
+    stream_count: u64,
+    end: PublishEnd,
+) {
+    info!(
+        "Subscription {} done ({:?}, {} streams): {}",
+        request_id, end.status, stream_count, end.reason
+    );
+    let reason = match ReasonPhrase::try_new(end.reason) {
+        Ok(reason) => reason,
+        Err(e) => {
+            error!("Failed to build PublishDone reason: {:?}", e);
+            return;
+        }
+    };
+    let publish_done = PublishDone::new(request_id, end.status, stream_count, reason);
+    if let Err(e) = control_stream_handler.send_impl(&publish_done).await {
+        error!("Failed to send PublishDone: {:?}", e);
+    }
+}
+
+/// Sends the init segment as the only object of the init track.
+async fn publish_init(
+    connection: Arc<Connection>,
+    publication: Publication,
+    mp4_path: Arc<String>,
+    idx: Arc<indexer::Mp4Index>,
+) -> PublishEnd {
+    let track_alias = publication.track_alias;
+    if !publication
+        .window
+        .contains(INIT_LOCATION.group, INIT_LOCATION.object)
+    {
+        info!(
+            "Subscription to {} starts past the init segment",
+            INIT_TRACK
+        );
+        return PublishEnd {
+            status: PublishDoneStatusCode::TrackEnded,
+            reason: "Subscription starts past the init segment".to_string(),
+        };
+    }
+    let publisher_priority: u8 = 128;
+    let init_buf = match File::open(&*mp4_path).and_then(|mut f| idx.read_init(&mut f)) {
+        Ok(buf) if !buf.is_empty() => buf,
+        Ok(_) => return PublishEnd::failed("Empty init segment".to_string()),
+        Err(e) => {
+            error!("Failed to read init bytes: {:?}", e);
+            return PublishEnd::failed(format!("Failed to read init segment: {}", e));
+        }
+    };
+    let init_len = init_buf.len();
+
+    if *publication.cancel.borrow() {
+        return PublishEnd {
+            status: PublishDoneStatusCode::SubscriptionEnded,
+            reason: "Unsubscribed".to_string(),
+        };
+    }
+    let send_stream = match connection.open_uni().await {
+        Ok(pending) => match pending.await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to complete open uni stream for init: {:?}", e);
+                return PublishEnd::failed(format!("Failed to open stream: {}", e));
+            }
+        },
+        Err(e) => {
+            error!("Failed to open uni stream for init: {:?}", e);
+            return PublishEnd::failed(format!("Failed to open stream: {}", e));
+        }
+    };
+    publication.streams.fetch_add(1, Ordering::Relaxed);
+    let send_stream = Arc::new(tokio::sync::Mutex::new(send_stream));
+
+    let sub_header = SubgroupHeader::new_with_explicit_id(
+        track_alias,
+        INIT_LOCATION.group,
+        INIT_LOCATION.subgroup,
+        publisher_priority,
+        true, // if false then extension headers must be set to None
+        true,
+    );
+    let header_info = HeaderInfo::Subgroup { header: sub_header };
+    let mut stream_handler = match SendDataStream::new(send_stream.clone(), header_info).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to create SendDataStream for init: {:?}", e);
+            return PublishEnd::failed(format!("Failed to start stream: {:?}", e));
+        }
+    };
+
+    let subgroup_obj = SubgroupObject {
+        object_id: INIT_LOCATION.object,
+        extension_headers: Some(vec![]),
+        object_status: Some(ObjectStatus::Normal),
+        payload: Some(Bytes::from(init_buf)),
+    };
+    let object = match Object::try_from_subgroup(
+        subgroup_obj,
+        track_alias,
+        INIT_LOCATION.group,
+        Some(INIT_LOCATION.subgroup),
+        publisher_priority,
+    ) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("Failed to build init Object from subgroup: {:?}", e);
+            return PublishEnd::failed(format!("Failed to build init object: {:?}", e));
+        }
+    };
+
+    let end = match stream_handler.send_object(&object, None).await {
+        Ok(()) => PublishEnd {
+            status: PublishDoneStatusCode::TrackEnded,
+            reason: "Sent the init segment".to_string(),
+        },
+        Err(e) => {
+            error!("Failed to send init object: {:?}", e);
+            PublishEnd::failed(format!("Failed to send init object: {:?}", e))
+        }
+    };
+    if let Err(e) = stream_handler.flush().await {
+        error!("Failed to flush init stream: {:?}", e);
+    }
+    if let Err(e) = stream_handler.finish().await {
+        error!("Failed to finish init stream: {:?}", e);
+    }
+    info!(
+        "Sent init segment on track {} ({} bytes)",
+        INIT_TRACK, init_len
+    );
+    end
+}
+
+/// The fragments a subscription publishes, as indices into
+/// `Mp4Index::frags` by group and then MP4 track. Each track's list is sent
+/// on one stream.
+struct MediaPlan {
+    groups: BTreeMap<u64, BTreeMap<u32, Vec<usize>>>,
+    /// Fragments the subscription covers, including any the
+    /// MAX_OBJECTS_PER_SUBGROUP cap leaves out of `groups`.
+    covered: usize,
+}
+
+/// Lists the fragments of `source` inside `window`, keeping the first
+/// `max_objects_per_subgroup` of each group and track.
+fn plan_media(
+    idx: &indexer::Mp4Index,
+    source: TrackSource,
+    window: &SubscribeWindow,
+    max_objects_per_subgroup: usize,
+) -> MediaPlan {
+    let mut groups: BTreeMap<u64, BTreeMap<u32, Vec<usize>>> = BTreeMap::new();
+    let mut covered = 0;
+    for (i, frag) in idx.frags.iter().enumerate() {
+        let loc = frag.location();
+        if source.carries(frag) && window.contains(loc.group, loc.object) {
+            covered += 1;
+            groups
+                .entry(frag.group)
+                .or_default()
+                .entry(frag.track_id)
+                .or_default()
+                .push(i);
+        }
+    }
+    for (group_id, per_track) in &mut groups {
+        for (track_id, frags) in per_track {
+            if frags.len() > max_objects_per_subgroup {
+                warn!(
+                    "Dropping {} of {} fragments of group {} track {} (MAX_OBJECTS_PER_SUBGROUP={})",
+                    frags.len() - max_objects_per_subgroup,
+                    frags.len(),
+                    group_id,
+                    track_id,
+                    max_objects_per_subgroup
+                );
+                frags.truncate(max_objects_per_subgroup);
+            }
+        }
+    }
+    MediaPlan { groups, covered }
+}
+
+/// Where `send_media` writes a subscription's subgroups: data streams on the
+/// relay session, or a recorder in tests.
+trait SubgroupSink {
+    type Stream: SubgroupStream;
+
+    /// Opens a data stream and writes the header of subgroup `subgroup_id`
+    /// of `group_id` on it.
+    async fn open(
+        &self,
+        track_alias: u64,
+        group_id: u64,
+        subgroup_id: u64,
+        publisher_priority: u8,
+    ) -> anyhow::Result<Self::Stream>;
+}
+
+/// A data stream carrying one subgroup.
+trait SubgroupStream {
+    /// Sends `object`, which follows `previous_object_id` in the subgroup.
+    async fn send(
+        &mut self,
+        object: SubgroupObject,
+        previous_object_id: Option<u64>,
+    ) -> anyhow::Result<()>;
+
+    /// Flushes what is buffered and finishes the stream.
+    async fn finish(self);
+}
+
+impl SubgroupSink for Connection {
+    type Stream = SubgroupDataStream;
+
+    async fn open(
+        &self,
+        track_alias: u64,
+        group_id: u64,
+        subgroup_id: u64,
+        publisher_priority: u8,
+    ) -> anyhow::Result<SubgroupDataStream> {
+        let send_stream = self.open_uni().await?.await?;
+        let send_stream = Arc::new(tokio::sync::Mutex::new(send_stream));
+        let header = SubgroupHeader::new_with_explicit_id(
+            track_alias,
+            group_id,
+            subgroup_id,
+            publisher_priority,
+            true,
+            true,
+        );
+        let stream = SendDataStream::new(send_stream, HeaderInfo::Subgroup { header })
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to create SendDataStream: {:?}", e))?;
+        Ok(SubgroupDataStream {
+            stream,
+            track_alias,
+            group_id,
+            subgroup_id,
+            publisher_priority,
+        })
+    }
+}
+
+/// A subgroup on a data stream of the relay session.
+struct SubgroupDataStream {
+    stream: SendDataStream,
+    track_alias: u64,
+    group_id: u64,
+    subgroup_id: u64,
+    publisher_priority: u8,
+}
+
+impl SubgroupStream for SubgroupDataStream {
+    async fn send(
+        &mut self,
+        object: SubgroupObject,
+        previous_object_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let object = Object::try_from_subgroup(
+            object,
+            self.track_alias,
+            self.group_id,
+            Some(self.subgroup_id),
+            self.publisher_priority,
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to build Object from subgroup: {:?}", e))?;
+        self.stream
+            .send_object(&object, previous_object_id)
+            .await
+            .map_err(|e| anyhow::anyhow!("{:?}", e))
+    }
+
+    async fn finish(mut self) {
+        if let Err(e) = self.stream.flush().await {
+            error!(
+                "Failed to flush stream for group {} subgroup {}: {:?}",
+                self.group_id, self.subgroup_id, e
+            );
+        }
+        if let Err(e) = self.stream.finish().await {
+            error!(
+                "Failed to finish stream for group {} subgroup {}: {:?}",
+                self.group_id, self.subgroup_id, e
+            );
+        }
+    }
+}
+
+/// Publishes the fragments `publication` covers on data streams of
+/// `connection`; see `send_media`.
+async fn publish_media(
+    connection: Arc<Connection>,
+    publication: Publication,
+    mp4_path: Arc<String>,
+    idx: Arc<indexer::Mp4Index>,
+    pacer: Arc<Pacer>,
+    max_objects_per_subgroup: usize,
+) -> PublishEnd {
+    // open the file once
+    let mut file = match File::open(&*mp4_path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Failed to open mp4 file for publishing: {:?}", e);
+            return PublishEnd::failed(format!("Failed to open media: {}", e));
+        }
+    };
+    send_media(
+        &*connection,
+        &mut file,
+        publication,
+        &idx,
+        &pacer,
+        max_objects_per_subgroup,
+    )
+    .await
+}
+
+/// Sends the fragments `publication` covers through `sink`, reading them from
+/// `file`: one subgroup per group and MP4 track, each group released when
+/// `pacer` says it is due.
+async fn send_media<S: SubgroupSink, R: Read + Seek>(
+    sink: &S,
+    file: &mut R,
+    publication: Publication,
+    idx: &indexer::Mp4Index,
+    pacer: &Pacer,
+    max_objects_per_subgroup: usize,
+) -> PublishEnd {
+    let Publication {
+        track_name,
+        track_alias,
+        source,
+        window,
+        streams,
+        mut cancel,
+    } = publication;
+    // publisher priority
+    let publisher_priority: u8 = 128;
+
+    let plan = plan_media(idx, source, &window, max_objects_per_subgroup);
+    let mut published_frags = 0;
+    for (group_id, per_track) in plan.groups {
+        let group_start = per_track
+            .values()
+            .flatten()
+            .map(|&i| idx.start_secs(&idx.frags[i]))
+            .fold(f64::INFINITY, f64::min);
+        tokio::select! {
+            _ = pacer.wait_for(group_start) => {}
+            _ = until_cancelled(&mut cancel) => break,
+        }
+        info!(
+            "Publishing group {} with {} fragments (total across tracks)",
+            group_id,
+            per_track.values().map(Vec::len).sum::<usize>()
+        );
+
+        // One subgroup, on its own unidirectional stream, per MP4 track
+        for (track_id, track_frags) in per_track {
+            info!(
+                "Publishing group {} track {} with {} fragments",
+                group_id,
+                track_id,
+                track_frags.len()
+            );
+
+            // No stream may open once the relay has unsubscribed
+            if *cancel.borrow() {
                 break;
             }
+            // Each track travels in its own subgroup; see indexer::Frag.
+            let subgroup_id = idx.frags[track_frags[0]].location().subgroup;
+            let mut stream = match sink
+                .open(track_alias, group_id, subgroup_id, publisher_priority)
+                .await
+            {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!(
+                        "Failed to open stream for group {} track {}: {:?}",
+                        group_id, track_id, e
+                    );
+                    continue;
+                }
+            };
+            streams.fetch_add(1, Ordering::Relaxed);
+
+            // send each fragment for this track
+            let mut prev_object_id: Option<u64> = None;
+            for &i in &track_frags {
+                if *cancel.borrow() {
+                    break;
+                }
+                let frag = &idx.frags[i];
+                let object_id_for_frag = frag.location().object;
+                let buf = match idx.read_fragment(file, frag) {
+                    Ok(buf) => buf,
+                    Err(e) => {
+                        error!("Failed to read fragment bytes: {:?}", e);
+                        break;
+                    }
+                };
+                let size = buf.len();
+
+                let subgroup_obj = SubgroupObject {
+                    object_id: object_id_for_frag,
+                    extension_headers: Some(vec![]),
+                    object_status: Some(ObjectStatus::Normal),
+                    payload: Some(Bytes::from(buf)),
+                };
+
+                if let Err(e) = stream.send(subgroup_obj, prev_object_id).await {
+                    error!(
+                        "Failed to send object for group {} track {} object {}: {:?}",
+                        group_id, track_id, object_id_for_frag, e
+                    );
+                    break;
+                }
+                debug!(
+                    "Sent object for group {} track {} object {} (size={})",
+                    group_id, track_id, object_id_for_frag, size
+                );
+                published_frags += 1;
+                prev_object_id = Some(object_id_for_frag);
+            }
+            stream.finish().await;
+        }
+
+        if *cancel.borrow() {
+            info!("Stopped publishing {} in group {}", track_name, group_id);
+            break;
+        }
+        info!("Finished publishing group {}", group_id);
+    }
+
+    if published_frags < plan.covered {
+        warn!(
+            "Published {} of {} fragments on {}",
+            published_frags, plan.covered, track_name
+        );
+    } else {
+        info!("Published all {} fragments on {}", plan.covered, track_name);
+    }
+
+    // An AbsoluteRange ending before the track does ends only the subscription
+    let status = match window.end_group {
+        Some(end)
+            if source
+                .last_location(idx)
+                .is_some_and(|(last, _)| end < last) =>
+        {
+            PublishDoneStatusCode::SubscriptionEnded
+        }
+        _ => PublishDoneStatusCode::TrackEnded,
+    };
+    PublishEnd {
+        status,
+        reason: format!(
+            "Published {} of {} fragments",
+            published_frags, plan.covered
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::fixtures::{frag, index, media};
+    use crate::pacing::PacingPolicy;
+    use crate::subscriptions::FetchTarget;
+    use crate::tracks::{FetchItem, MEDIA_TRACK};
+    use moqtail::model::control::constant::FetchType;
+    use moqtail::model::control::fetch::{JoiningFetchProps, StandAloneFetchProps};
+    use std::io::Cursor;
+
+    const WHOLE_TRACK: SubscribeWindow = SubscribeWindow {
+        start: (0, 0),
+        end_group: None,
+        largest: None,
+    };
+
+    // Group 0 holds three video and two audio fragments, group 1 one of each.
+    fn two_track_index() -> indexer::Mp4Index {
+        index(vec![
+            frag(1, 0, 0, 0),
+            frag(2, 0, 0, 1),
+            frag(1, 500, 0, 2),
+            frag(2, 500, 0, 3),
+            frag(1, 1000, 0, 4),
+            frag(1, 2000, 1, 0),
+            frag(2, 2000, 1, 1),
+        ])
+    }
+
+    fn sent(plan: &MediaPlan) -> usize {
+        plan.groups
+            .values()
+            .flat_map(|g| g.values())
+            .map(Vec::len)
+            .sum()
+    }
+
+    #[test]
+    fn plan_media_sends_every_fragment_without_a_cap() {
+        let idx = two_track_index();
+        let plan = plan_media(&idx, TrackSource::Media, &WHOLE_TRACK, usize::MAX);
+
+        assert_eq!(sent(&plan), 7);
+        assert_eq!(plan.covered, 7);
+        assert_eq!(plan.groups[&0][&1], vec![0, 2, 4]);
+        assert_eq!(plan.groups[&0][&2], vec![1, 3]);
+        assert_eq!(plan.groups[&1][&1], vec![5]);
+        assert_eq!(plan.groups[&1][&2], vec![6]);
+    }
+
+    #[test]
+    fn plan_media_caps_objects_per_group_and_track() {
+        let idx = two_track_index();
+        let plan = plan_media(&idx, TrackSource::Media, &WHOLE_TRACK, 2);
+
+        assert_eq!(sent(&plan), 6);
+        assert_eq!(plan.covered, 7);
+        assert_eq!(plan.groups[&0][&1], vec![0, 2]);
+        assert_eq!(plan.groups[&0][&2], vec![1, 3]);
+    }
+
+    #[test]
+    fn plan_media_keeps_to_the_track_and_window() {
+        let idx = two_track_index();
+        let window = SubscribeWindow {
+            start: (0, 2),
+            end_group: Some(0),
+            largest: None,
+        };
+        let plan = plan_media(&idx, TrackSource::SingleTrack(1), &window, usize::MAX);
+
+        assert_eq!(sent(&plan), 2);
+        assert_eq!(plan.groups[&0][&1], vec![2, 4]);
+        assert!(!plan.groups.contains_key(&1));
+    }
+
+    /// A subgroup `send_media` wrote: its group, subgroup and objects as
+    /// (object ID, previous object ID, payload).
+    #[derive(Debug)]
+    struct SentSubgroup {
+        group_id: u64,
+        subgroup_id: u64,
+        objects: Vec<(u64, Option<u64>, Vec<u8>)>,
+    }
+
+    /// Records the subgroups sent through it once their streams finish.
+    #[derive(Default)]
+    struct Recorder {
+        finished: Arc<std::sync::Mutex<Vec<SentSubgroup>>>,
+    }
+
+    struct RecordedStream {
+        subgroup: SentSubgroup,
+        finished: Arc<std::sync::Mutex<Vec<SentSubgroup>>>,
+    }
+
+    impl SubgroupSink for Recorder {
+        type Stream = RecordedStream;
+
+        async fn open(
+            &self,
+            _track_alias: u64,
+            group_id: u64,
+            subgroup_id: u64,
+            _publisher_priority: u8,
+        ) -> anyhow::Result<RecordedStream> {
+            Ok(RecordedStream {
+                subgroup: SentSubgroup {
+                    group_id,
+                    subgroup_id,
+                    objects: vec![],
+                },
+                finished: self.finished.clone(),
+            })
+        }
+    }
+
+    impl SubgroupStream for RecordedStream {
+        async fn send(
+            &mut self,
+            object: SubgroupObject,
+            previous_object_id: Option<u64>,
+        ) -> anyhow::Result<()> {
+            let payload = object.payload.map(|p| p.to_vec()).unwrap_or_default();
+            self.subgroup
+                .objects
+                .push((object.object_id, previous_object_id, payload));
+            Ok(())
+        }
+
+        async fn finish(self) {
+            self.finished.lock().unwrap().push(self.subgroup);
+        }
+    }
+
+    fn publication(source: TrackSource, cancel: Cancel) -> (Publication, Arc<AtomicU64>) {
+        let streams = Arc::new(AtomicU64::new(0));
+        let publication = Publication {
+            track_name: MEDIA_TRACK.to_string(),
+            track_alias: 1,
+            source,
+            window: WHOLE_TRACK,
+            streams: streams.clone(),
+            cancel,
+        };
+        (publication, streams)
+    }
+
+    #[tokio::test]
+    async fn send_media_sends_every_indexed_fragment() {
+        let idx = two_track_index();
+        let mut file = Cursor::new(media(&idx));
+        let recorder = Recorder::default();
+        let (_cancel_tx, cancel) = watch::channel(false);
+        let (publication, streams) = publication(TrackSource::Media, cancel);
+        let pacer = Pacer::new(PacingPolicy::Burst);
+
+        let end = send_media(&recorder, &mut file, publication, &idx, &pacer, usize::MAX).await;
+
+        assert_eq!(end.reason, "Published 7 of 7 fragments");
+        let sent = recorder.finished.lock().unwrap();
+        assert_eq!(sent.len(), 4);
+        assert_eq!(streams.load(Ordering::Relaxed), 4);
+        let mut delivered = 0;
+        for subgroup in sent.iter() {
+            let mut previous = None;
+            for (object_id, previous_object_id, payload) in &subgroup.objects {
+                let frag = idx
+                    .frags
+                    .iter()
+                    .find(|f| {
+                        let loc = f.location();
+                        (loc.group, loc.subgroup, loc.object)
+                            == (subgroup.group_id, subgroup.subgroup_id, *object_id)
+                    })
+                    .expect("object at an indexed location");
+                assert_eq!(*previous_object_id, previous);
+                assert_eq!(*payload, idx.read_fragment(&mut file, frag).unwrap());
+                previous = Some(*object_id);
+                delivered += 1;
+            }
+        }
+        assert_eq!(delivered, idx.frags.len());
+    }
+
+    #[tokio::test]
+    async fn send_media_reports_fragments_the_cap_drops() {
+        let idx = two_track_index();
+        let mut file = Cursor::new(media(&idx));
+        let recorder = Recorder::default();
+        let (_cancel_tx, cancel) = watch::channel(false);
+        let (publication, _) = publication(TrackSource::Media, cancel);
+        let pacer = Pacer::new(PacingPolicy::Burst);
+
+        let end = send_media(&recorder, &mut file, publication, &idx, &pacer, 2).await;
+
+        assert_eq!(end.reason, "Published 6 of 7 fragments");
+        let sent = recorder.finished.lock().unwrap();
+        let objects: usize = sent.iter().map(|s| s.objects.len()).sum();
+        assert_eq!(objects, 6);
+    }
+
+    #[tokio::test]
+    async fn send_media_opens_no_stream_once_cancelled() {
+        let idx = two_track_index();
+        let mut file = Cursor::new(media(&idx));
+        let recorder = Recorder::default();
+        let (cancel_tx, cancel) = watch::channel(false);
+        cancel_tx.send(true).unwrap();
+        let (publication, streams) = publication(TrackSource::Media, cancel);
+        let pacer = Pacer::new(PacingPolicy::Burst);
+
+        send_media(&recorder, &mut file, publication, &idx, &pacer, usize::MAX).await;
+
+        assert!(recorder.finished.lock().unwrap().is_empty());
+        assert_eq!(streams.load(Ordering::Relaxed), 0);
+    }
+
+    fn standalone_fetch(
+        track_name: &str,
+        start: (u64, u64),
+        end: (u64, u64),
+        group_order: GroupOrder,
+    ) -> Fetch {
+        Fetch {
+            request_id: 9,
+            subscriber_priority: 1,
+            group_order,
+            fetch_type: FetchType::StandAlone,
+            standalone_fetch_props: Some(StandAloneFetchProps {
+                track_namespace: Tuple::from_utf8_path(TRACK_NAMESPACE),
+                track_name: track_name.to_string(),
+                start_location: Location::new(start.0, start.1),
+                end_location: Location::new(end.0, end.1),
+            }),
+            joining_fetch_props: None,
+            parameters: vec![],
+        }
+    }
+
+    fn joining_fetch(joining_request_id: u64, joining_start: u64) -> Fetch {
+        Fetch {
+            request_id: 9,
+            subscriber_priority: 1,
+            group_order: GroupOrder::Original,
+            fetch_type: FetchType::RelativeFetch,
+            standalone_fetch_props: None,
+            joining_fetch_props: Some(JoiningFetchProps {
+                joining_request_id,
+                joining_start,
+            }),
+            parameters: vec![],
+        }
+    }
+
+    fn accepted(
+        fetch: &Fetch,
+        idx: &indexer::Mp4Index,
+        subscriptions: &Subscriptions,
+    ) -> (Vec<(u64, u64)>, bool, (u64, u64)) {
+        let (plan, largest) = accept_fetch(fetch, idx, subscriptions).unwrap();
+        let objects = plan
+            .objects
+            .iter()
+            .map(|item| {
+                let loc = item.location(idx);
+                (loc.group, loc.object)
+            })
+            .collect();
+        (objects, plan.end_of_track, (largest.group, largest.object))
+    }
+
+    #[test]
+    fn accept_fetch_plans_a_standalone_fetch() {
+        let idx = two_track_index();
+        let subscriptions = Subscriptions::default();
+
+        // An end object of 0 covers the whole end group
+        let fetch = standalone_fetch(MEDIA_TRACK, (0, 3), (0, 0), GroupOrder::Ascending);
+        assert_eq!(
+            accepted(&fetch, &idx, &subscriptions),
+            (vec![(0, 3), (0, 4)], false, (0, 4))
+        );
+
+        let fetch = standalone_fetch(MEDIA_TRACK, (0, 3), (1, 0), GroupOrder::Descending);
+        assert_eq!(
+            accepted(&fetch, &idx, &subscriptions),
+            (vec![(1, 0), (1, 1), (0, 3), (0, 4)], true, (1, 1))
+        );
+
+        let fetch = standalone_fetch("demo/track/2", (0, 0), (0, 0), GroupOrder::Ascending);
+        assert_eq!(
+            accepted(&fetch, &idx, &subscriptions),
+            (vec![(0, 1), (0, 3)], false, (0, 3))
+        );
+    }
+
+    #[test]
+    fn accept_fetch_plans_the_init_track() {
+        let idx = two_track_index();
+        let fetch = standalone_fetch(INIT_TRACK, (0, 0), (0, 1), GroupOrder::Ascending);
+        let (plan, largest) = accept_fetch(&fetch, &idx, &Subscriptions::default()).unwrap();
+        assert!(matches!(plan.objects[..], [FetchItem::Init]));
+        assert!(plan.end_of_track);
+        assert_eq!(largest, INIT_LOCATION);
+    }
+
+    #[test]
+    fn accept_fetch_joins_a_subscription_up_to_its_largest() {
+        let idx = two_track_index();
+        let subscriptions = Subscriptions::default();
+        subscriptions.insert(4, MEDIA_TRACK.to_string(), Some((1, 0)));
+
+        assert_eq!(
+            accepted(&joining_fetch(4, 1), &idx, &subscriptions),
+            (
+                vec![(0, 0), (0, 1), (0, 2), (0, 3), (0, 4), (1, 0)],
+                false,
+                (1, 0)
+            )
+        );
+    }
+
+    fn subscribe(
+        namespace: &str,
+        track_name: &str,
+        filter_type: FilterType,
+        start: Option<(u64, u64)>,
+        end_group: Option<u64>,
+    ) -> Subscribe {
+        Subscribe {
+            request_id: 2,
+            track_namespace: Tuple::from_utf8_path(namespace),
+            track_name: track_name.to_string(),
+            subscriber_priority: 1,
+            group_order: GroupOrder::Original,
+            forward: true,
+            filter_type,
+            start_location: start.map(|(group, object)| Location::new(group, object)),
+            end_group,
+            subscribe_parameters: vec![],
+        }
+    }
+
+    #[test]
+    fn accept_subscribe_rejects_with_the_matching_code() {
+        let idx = two_track_index();
+        let pacer = Pacer::new(PacingPolicy::Burst);
+        let cases = [
+            (
+                subscribe("other", MEDIA_TRACK, FilterType::LatestObject, None, None),
+                SubscribeErrorCode::TrackDoesNotExist,
+            ),
+            (
+                subscribe(
+                    TRACK_NAMESPACE,
+                    "nope",
+                    FilterType::LatestObject,
+                    None,
+                    None,
+                ),
+                SubscribeErrorCode::TrackDoesNotExist,
+            ),
+            (
+                subscribe(
+                    TRACK_NAMESPACE,
+                    "demo/track/9",
+                    FilterType::LatestObject,
+                    None,
+                    None,
+                ),
+                SubscribeErrorCode::TrackDoesNotExist,
+            ),
+            (
+                subscribe(
+                    TRACK_NAMESPACE,
+                    MEDIA_TRACK,
+                    FilterType::AbsoluteStart,
+                    Some((1, 2)),
+                    None,
+                ),
+                SubscribeErrorCode::InvalidRange,
+            ),
+            (
+                subscribe(
+                    TRACK_NAMESPACE,
+                    "demo/track/2",
+                    FilterType::AbsoluteRange,
+                    Some((1, 2)),
+                    Some(3),
+                ),
+                SubscribeErrorCode::InvalidRange,
+            ),
+            (
+                subscribe(
+                    TRACK_NAMESPACE,
+                    MEDIA_TRACK,
+                    FilterType::AbsoluteRange,
+                    Some((1, 0)),
+                    Some(0),
+                ),
+                SubscribeErrorCode::InvalidRange,
+            ),
+            (
+                subscribe(
+                    TRACK_NAMESPACE,
+                    MEDIA_TRACK,
+                    FilterType::AbsoluteStart,
+                    None,
+                    None,
+                ),
+                SubscribeErrorCode::InvalidRange,
+            ),
+        ];
+        for (sub, code) in cases {
+            match accept_subscribe(&sub, &idx, &pacer) {
+                Err((got, _)) => assert_eq!(got, code, "{:?}", sub),
+                Ok(accepted) => panic!("accepted {:?} as {:?}", sub, accepted),
+            }
+        }
+    }
+
+    #[test]
+    fn accept_subscribe_routes_hosted_tracks() {
+        let idx = two_track_index();
+        let pacer = Pacer::new(PacingPolicy::Burst);
+        for (track_name, source) in [
+            (INIT_TRACK, TrackSource::Init),
+            (MEDIA_TRACK, TrackSource::Media),
+            ("demo/track/2", TrackSource::SingleTrack(2)),
+        ] {
+            let sub = subscribe(
+                TRACK_NAMESPACE,
+                track_name,
+                FilterType::AbsoluteStart,
+                Some((0, 0)),
+                None,
+            );
+            let (got, _) = accept_subscribe(&sub, &idx, &pacer).unwrap();
+            assert_eq!(got, source);
+        }
+    }
+
+    #[test]
+    fn accept_fetch_rejects_with_the_matching_code() {
+        let idx = two_track_index();
+        let subscriptions = Subscriptions::default();
+        subscriptions.insert(4, MEDIA_TRACK.to_string(), None);
+        let mut other_namespace =
+            standalone_fetch(MEDIA_TRACK, (0, 0), (1, 0), GroupOrder::Ascending);
+        if let Some(props) = other_namespace.standalone_fetch_props.as_mut() {
+            props.track_namespace = Tuple::from_utf8_path("other");
+        }
+        let mut without_props =
+            standalone_fetch(MEDIA_TRACK, (0, 0), (1, 0), GroupOrder::Ascending);
+        without_props.standalone_fetch_props = None;
+
+        let cases = [
+            (joining_fetch(5, 0), FetchErrorCode::InvalidJoiningRequestId),
+            (joining_fetch(4, 0), FetchErrorCode::NoObjects),
+            (other_namespace, FetchErrorCode::TrackDoesNotExist),
+            (
+                standalone_fetch("nope", (0, 0), (1, 0), GroupOrder::Ascending),
+                FetchErrorCode::TrackDoesNotExist,
+            ),
+            (
+                standalone_fetch("demo/track/9", (0, 0), (1, 0), GroupOrder::Ascending),
+                FetchErrorCode::TrackDoesNotExist,
+            ),
+            (
+                standalone_fetch(MEDIA_TRACK, (1, 0), (0, 0), GroupOrder::Ascending),
+                FetchErrorCode::InvalidRange,
+            ),
+            (without_props, FetchErrorCode::InvalidRange),
+            (
+                standalone_fetch(MEDIA_TRACK, (5, 0), (6, 0), GroupOrder::Ascending),
+                FetchErrorCode::NoObjects,
+            ),
+            (
+                standalone_fetch(INIT_TRACK, (1, 0), (1, 0), GroupOrder::Ascending),
+                FetchErrorCode::NoObjects,
+            ),
+        ];
+        for (fetch, code) in cases {
+            match accept_fetch(&fetch, &idx, &subscriptions) {
+                Err((got, _)) => assert_eq!(got, code, "{:?}", fetch),
+                Ok(_) => panic!("accepted {:?}", fetch),
+            }
+        }
+    }
+
+    #[test]
+    fn range_fetch_and_subscribe_deliver_the_same_payloads() {
+        let idx = two_track_index();
+        let mut file = Cursor::new(media(&idx));
+        for frag in &idx.frags {
+            let loc = frag.location();
+            let at = (loc.group, loc.object);
+
+            // GET /range
+            let range = idx.location_range(at, at);
+            assert_eq!(range.len(), 1);
+            let ranged = idx
+                .read_fragment(&mut file, &idx.frags[range.start])
+                .unwrap();
+
+            // FETCH, over MOQ or POST /fetch
+            let target = FetchTarget {
+                track_name: MEDIA_TRACK.to_string(),
+                start: at,
+                end: at,
+            };
+            let plan = plan_fetch(&idx, TrackSource::Media, &target, false);
+            assert_eq!(plan.objects.len(), 1);
+            let item = plan.objects[0];
+            let fetched = fetch_object(item.location(&idx), item.read(&idx, &mut file).unwrap());
+            assert_eq!(
+                (fetched.group_id, fetched.subgroup_id, fetched.object_id),
+                (loc.group, loc.subgroup, loc.object)
+            );
+
+            // SUBSCRIBE
+            let window = SubscribeWindow {
+                start: at,
+                end_group: Some(loc.group),
+                largest: None,
+            };
+            let published = plan_media(&idx, TrackSource::Media, &window, usize::MAX);
+            let first = &idx.frags[published.groups[&loc.group][&frag.track_id][0]];
+            assert_eq!(first.location(), loc);
+            let subscribed = idx.read_fragment(&mut file, first).unwrap();
+
+            assert_eq!(fetched.payload.as_deref(), Some(&ranged[..]));
+            assert_eq!(subscribed, ranged);
         }
     }
-    Ok(())
 }
@@ -12,15 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::indexer;
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use moqtail::model::control::control_message::ControlMessageTrait;
+use crate::error::PublisherError;
+use crate::indexer::{self, LocationSpan, ObjectLocation};
+use crate::subscriptions::{FetchResolveError, Subscriptions};
+use crate::supervisor::SessionMetrics;
+use crate::tracks::{TrackSource, fetch_object, plan_fetch};
+use bytes::{BufMut, Bytes, BytesMut};
+use moqtail::model::control::constant::GroupOrder;
+use moqtail::model::control::control_message::{ControlMessage, ControlMessageTrait};
 use moqtail::model::control::fetch::Fetch;
-use moqtail::model::data::fetch_object::FetchObject;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+// Chunks a streamed response may read ahead of the client. Bodies are sent
+// one init segment or fragment per chunk.
+const STREAM_BUFFER_CHUNKS: usize = 4;
+
+type ChunkSender = mpsc::Sender<Result<Bytes, PublisherError>>;
 
 //TODO: should be moved to moqtail-rs structure
 #[derive(Deserialize)]
@@ -33,6 +45,109 @@ pub struct RangeQuery {
     pub end_group_id: u64,
     #[serde(rename = "EndObjectId")]
     pub end_object_id: u32,
+    /// Trim the range to the available content instead of rejecting it when
+    /// it reaches past either end.
+    #[serde(rename = "Clamp", default)]
+    pub clamp: bool,
+}
+
+/// Response header naming the span actually returned and the span available,
+/// in the manner of Content-Range: `3:0-5:7/0:0-120:3`.
+pub const OBJECT_RANGE_HEADER: &str = "X-Object-Range";
+
+/// `?track=1&time=12.5`: a point on the presentation timeline, in seconds.
+#[derive(Deserialize)]
+pub struct LocateQuery {
+    pub track: u32,
+    pub time: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LocateResponse {
+    pub group_id: u64,
+    pub subgroup_id: u64,
+    pub object_id: u64,
+    /// Presentation time at which the fragment starts, in seconds.
+    pub start_time: f64,
+}
+
+/// `?start=12.5&end=20.0`: a span of the presentation timeline in seconds.
+#[derive(Deserialize)]
+pub struct TimeRangeQuery {
+    pub start: f64,
+    pub end: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TrackInfo {
+    pub track_id: u32,
+    pub timescale: u32,
+    /// Added to the track's media time to place it on the presentation
+    /// timeline, in `timescale` units.
+    pub presentation_offset: i64,
+}
+
+pub async fn handle_tracks_request(
+    idx: Arc<indexer::Mp4Index>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut tracks: Vec<TrackInfo> = idx
+        .timescale
+        .iter()
+        .map(|(&track_id, &timescale)| TrackInfo {
+            track_id,
+            timescale,
+            presentation_offset: idx.presentation_offset(track_id),
+        })
+        .collect();
+    tracks.sort_by_key(|t| t.track_id);
+    Ok(warp::reply::json(&tracks))
+}
+
+/// Relay session counters for Prometheus.
+pub async fn handle_metrics_request(
+    metrics: Arc<SessionMetrics>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::with_header(
+        metrics.render(),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+pub async fn handle_locate_request(
+    query: LocateQuery,
+    idx: Arc<indexer::Mp4Index>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let frag = idx.frag_at(query.track, query.time).ok_or_else(|| {
+        PublisherError::NotFound(format!(
+            "Track {} has no fragment at {:.3}s",
+            query.track, query.time
+        ))
+    })?;
+    let loc = frag.location();
+    Ok(warp::reply::json(&LocateResponse {
+        group_id: loc.group,
+        subgroup_id: loc.subgroup,
+        object_id: loc.object,
+        start_time: idx.start_secs(frag),
+    }))
+}
+
+pub async fn handle_track_init_request(
+    track_id: u32,
+    idx: Arc<indexer::Mp4Index>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("GET init for track {}", track_id);
+    let init = idx
+        .track_init(track_id)
+        .ok_or_else(|| PublisherError::NotFound(format!("Unknown track {}", track_id)))?;
+    Ok(warp::reply::with_header(
+        init.to_vec(),
+        "Content-Type",
+        "video/mp4",
+    ))
 }
 
 //TODO: Should be moved to moqtail answer
@@ -41,202 +156,495 @@ pub async fn handle_range_request(
     mp4_path: Arc<String>,
     idx: Arc<indexer::Mp4Index>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let start_secs = idx
+        .frag_at_location(query.start_group_id, query.start_object_id as u64)
+        .map(|f| idx.start_secs(f));
     println!(
-        "GET range: group {}:{} → {}:{}",
-        query.start_group_id, query.start_object_id, query.end_group_id, query.end_object_id
+        "GET range: group {}:{} → {}:{} (starts at {})",
+        query.start_group_id,
+        query.start_object_id,
+        query.end_group_id,
+        query.end_object_id,
+        start_secs.map_or("?".to_string(), |t| format!("{t:.3}s"))
     );
 
-    let mut file = File::open(&*mp4_path).unwrap();
-    let mut response_bytes = Vec::new();
-
-    // Append init segment
-    let init_len = (idx.init.end - idx.init.start) as usize;
-    let mut init_buf = vec![0u8; init_len];
-    file.seek(SeekFrom::Start(idx.init.start)).unwrap();
-    file.read_exact(&mut init_buf).unwrap();
-    response_bytes.extend(init_buf);
-
-    // Append requested fragments
-    for frag in &idx.frags {
-        let in_range = if query.start_group_id == query.end_group_id {
-            frag.group == query.start_group_id
-                && frag.object >= query.start_object_id
-                && frag.object <= query.end_object_id
-        } else {
-            (frag.group == query.start_group_id && frag.object >= query.start_object_id)
-                || (frag.group > query.start_group_id && frag.group < query.end_group_id)
-                || (frag.group == query.end_group_id && frag.object <= query.end_object_id)
-        };
+    let (frags, object_range) = resolve_range(&query, &idx)?;
+    let reply = mp4_range_reply(mp4_path, idx, frags).await?;
+    Ok(warp::reply::with_header(
+        reply,
+        OBJECT_RANGE_HEADER,
+        object_range,
+    ))
+}
 
-        if in_range {
-            let moof_size = frag.mdat_start - frag.moof_start;
-            let total_size = moof_size + frag.mdat_size;
-            let mut frag_buf = vec![0u8; total_size as usize];
-            file.seek(SeekFrom::Start(frag.moof_start)).unwrap();
-            file.read_exact(&mut frag_buf).unwrap();
-            response_bytes.extend(frag_buf);
+/// Checks `query` against the groups the index holds and resolves it to the
+/// fragments it returns, along with the `OBJECT_RANGE_HEADER` value naming
+/// them.
+fn resolve_range(
+    query: &RangeQuery,
+    idx: &indexer::Mp4Index,
+) -> Result<(Range<usize>, String), PublisherError> {
+    let mut start = (query.start_group_id, query.start_object_id as u64);
+    let mut end = (query.end_group_id, query.end_object_id as u64);
+    let requested = LocationSpan::new(start, end);
+    let available = LocationSpan::of(&idx.frags);
+    if start > end {
+        return Err(PublisherError::InvertedRange {
+            message: format!("Range {} ends before it starts", requested),
+            available,
+        });
+    }
+    let Some(available) = available else {
+        return Err(PublisherError::RangeNotSatisfiable {
+            message: "The index holds no fragments".to_string(),
+            available: None,
+        });
+    };
+    if !query.clamp && (start.0 < available.start_group_id || end.0 > available.end_group_id) {
+        return Err(PublisherError::RangeNotSatisfiable {
+            message: format!("Range {} is not within {}", requested, available),
+            available: Some(available),
+        });
+    }
+    // Clients cannot know how many objects the edge groups hold, so an object
+    // id past either end of a group that is available only trims the range.
+    start = start.max(available.start());
+    end = end.min(available.end());
+    if start > end {
+        return Err(PublisherError::RangeNotSatisfiable {
+            message: format!("Range {} is not within {}", requested, available),
+            available: Some(available),
+        });
+    }
+
+    let frags = idx.location_range(start, end);
+    let Some(returned) = LocationSpan::of(&idx.frags[frags.clone()]) else {
+        return Err(PublisherError::RangeNotSatisfiable {
+            message: format!("Range {} holds no fragments", requested),
+            available: Some(available),
+        });
+    };
+    Ok((frags, format!("{}/{}", returned, available)))
+}
+
+pub async fn handle_time_range_request(
+    query: TimeRangeQuery,
+    mp4_path: Arc<String>,
+    idx: Arc<indexer::Mp4Index>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let available = LocationSpan::of(&idx.frags);
+    if query.start.is_nan() || query.end.is_nan() || query.start > query.end {
+        return Err(PublisherError::InvertedRange {
+            message: format!("Invalid time range {}s → {}s", query.start, query.end),
+            available,
         }
+        .into());
     }
+    let frags = idx.time_range(query.start, query.end);
+    let (Some(returned), Some(available)) =
+        (LocationSpan::of(&idx.frags[frags.clone()]), available)
+    else {
+        return Err(PublisherError::RangeNotSatisfiable {
+            message: format!(
+                "No fragments between {:.3}s and {:.3}s",
+                query.start, query.end
+            ),
+            available,
+        }
+        .into());
+    };
+    println!(
+        "GET range: {:.3}s → {:.3}s (group {})",
+        query.start, query.end, returned
+    );
+    let reply = mp4_range_reply(mp4_path, idx, frags).await?;
+    Ok(warp::reply::with_header(
+        reply,
+        OBJECT_RANGE_HEADER,
+        format!("{}/{}", returned, available),
+    ))
+}
 
+/// Init segment followed by `frags`, as a playable MP4 body.
+async fn mp4_range_reply(
+    mp4_path: Arc<String>,
+    idx: Arc<indexer::Mp4Index>,
+    frags: Range<usize>,
+) -> Result<impl warp::Reply, PublisherError> {
+    let mut file = open_media(&mp4_path).await?;
+    let body = stream_body(move |tx| {
+        if !send_chunk(tx, idx.read_init(&mut file)?.into()) {
+            return Ok(());
+        }
+        for frag in &idx.frags[frags] {
+            if !send_chunk(tx, idx.read_fragment(&mut file, frag)?.into()) {
+                break;
+            }
+        }
+        Ok(())
+    });
     Ok(warp::reply::with_header(
-        response_bytes,
+        warp::reply::Response::new(body),
         "Content-Type",
         "video/mp4",
     ))
 }
 
+/// Opens the media file without blocking the runtime. Failing here, before
+/// any of the body is sent, still lets the client see a proper error status.
+async fn open_media(mp4_path: &str) -> Result<File, PublisherError> {
+    Ok(tokio::fs::File::open(mp4_path).await?.into_std().await)
+}
+
+/// Builds a chunked response body from the chunks `produce` sends. `produce`
+/// runs on the blocking pool so file reads stay off the async runtime, and
+/// its sends block while the client is behind, which bounds what a request
+/// holds in memory to `STREAM_BUFFER_CHUNKS` chunks.
+fn stream_body<F>(produce: F) -> warp::hyper::Body
+where
+    F: FnOnce(&ChunkSender) -> Result<(), PublisherError> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = produce(&tx) {
+            println!("Failed to stream response: {}", e);
+            // Ending the body with an error aborts the transfer, so the
+            // client never mistakes a truncated response for a complete one.
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    warp::hyper::Body::wrap_stream(ReceiverStream::new(rx))
+}
+
+/// Queues one chunk of a streamed body. Returns false once the client has
+/// gone away, after which the producer should stop reading.
+fn send_chunk(tx: &ChunkSender, chunk: Bytes) -> bool {
+    tx.blocking_send(Ok(chunk)).is_ok()
+}
+
 //TODO: Should be moved to moqtail answer
 pub async fn handle_fetch_request(
     body: Bytes,
     mp4_path: Arc<String>,
     idx: Arc<indexer::Mp4Index>,
-) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    subscriptions: Arc<Subscriptions>,
+) -> Result<impl warp::Reply, warp::Rejection> {
     println!("POST fetch request with {} bytes", body.len());
 
     // Deserialize the Fetch request - the body should contain the full serialized message
-    let mut bytes = body;
+    let fetch = decode_fetch(body)?;
 
-    // Skip the control message type (1 byte)
-    if bytes.is_empty() {
-        return Ok(Box::new(warp::reply::with_status(
-            "Empty request body".to_string(),
-            warp::http::StatusCode::BAD_REQUEST,
-        )));
+    println!("Parsed Fetch request: {:?}", fetch);
+
+    // Joining fetches take their track and range from the subscription they
+    // join.
+    let target = subscriptions.resolve_fetch(&fetch).map_err(|e| match e {
+        FetchResolveError::Malformed(_) => PublisherError::BadRequest(e.to_string()),
+        _ => PublisherError::NotFound(e.to_string()),
+    })?;
+
+    println!(
+        "Fetch range: group {}:{} → {}:{}",
+        target.start.0, target.start.1, target.end.0, target.end.1
+    );
+
+    let source = TrackSource::for_fetch(&idx, &fetch, &target)
+        .ok_or_else(|| PublisherError::NotFound(format!("No track {}", target.track_name)))?;
+    let available = source.span(&idx).ok_or_else(|| {
+        PublisherError::NotFound(format!("Track {} holds no objects", target.track_name))
+    })?;
+    let requested = LocationSpan::new(target.start, target.end);
+    if target.start > target.end {
+        return Err(PublisherError::InvertedRange {
+            message: format!("Fetch range {} ends before it starts", requested),
+            available: Some(available),
+        }
+        .into());
     }
-    bytes.advance(1); // Skip message type
+    let plan = plan_fetch(
+        &idx,
+        source,
+        &target,
+        fetch.group_order == GroupOrder::Descending,
+    );
+    if plan.objects.is_empty() {
+        return Err(PublisherError::RangeNotSatisfiable {
+            message: format!(
+                "Fetch range {} holds no objects of {}",
+                requested, target.track_name
+            ),
+            available: Some(available),
+        }
+        .into());
+    }
+    let mut file = open_media(&mp4_path).await?;
+    let body = stream_body(move |tx| {
+        let mut sent = 0;
+        for item in plan.objects {
+            let payload = item.read(&idx, &mut file)?;
+            if !send_fetch_object(tx, item.location(&idx), payload, &mut sent)? {
+                break;
+            }
+        }
+        println!("Sent {} bytes of serialized FetchObjects", sent);
+        Ok(())
+    });
+
+    Ok(warp::reply::with_header(
+        warp::reply::Response::new(body),
+        "Content-Type",
+        "application/octet-stream",
+    ))
+}
+
+/// Decodes a request body holding exactly one framed control message, which
+/// must be a FETCH: a varint message type, a 16-bit payload length and the
+/// payload itself.
+fn decode_fetch(body: Bytes) -> Result<Fetch, PublisherError> {
+    let bad = PublisherError::BadRequest;
+    let fetch = match ControlMessage::deserialize(&mut body.clone()) {
+        Ok(ControlMessage::Fetch(fetch)) => fetch,
+        Ok(other) => return Err(bad(format!("Expected a FETCH message, got {:?}", other))),
+        Err(e) => return Err(bad(format!("Failed to parse Fetch request: {:?}", e))),
+    };
 
-    // Skip the payload length (2 bytes)
-    if bytes.len() < 2 {
-        return Ok(Box::new(warp::reply::with_status(
-            "Message too short".to_string(),
-            warp::http::StatusCode::BAD_REQUEST,
+    // Whatever moqtail tolerates, the body must be the declared payload and
+    // the FETCH all of it. The top two bits of a varint give its length.
+    let header_len = (1usize << (body[0] >> 6)) + 2;
+    let declared = match body.get(header_len - 2..header_len) {
+        Some(&[hi, lo]) => u16::from_be_bytes([hi, lo]) as usize,
+        _ => return Err(bad("Truncated FETCH header".to_string())),
+    };
+    let mut payload = body.slice(header_len.min(body.len())..);
+    if payload.len() != declared {
+        return Err(bad(format!(
+            "FETCH declares a {}-byte payload but the body holds {} bytes",
+            declared,
+            payload.len()
+        )));
+    }
+    Fetch::parse_payload(&mut payload)
+        .map_err(|e| bad(format!("Failed to parse Fetch request: {:?}", e)))?;
+    if !payload.is_empty() {
+        return Err(bad(format!(
+            "{} unparsed bytes at the end of the FETCH payload",
+            payload.len()
         )));
     }
-    bytes.advance(2); // Skip payload length
+    Ok(*fetch)
+}
+
+/// Serializes `payload` as the FetchObject at `loc` and queues it with a u32
+/// length prefix. Returns false once the client has gone away.
+fn send_fetch_object(
+    tx: &ChunkSender,
+    loc: ObjectLocation,
+    payload: Vec<u8>,
+    sent: &mut usize,
+) -> Result<bool, PublisherError> {
+    let serialized = fetch_object(loc, payload)
+        .serialize()
+        .map_err(|e| PublisherError::Serialization(format!("FetchObject at {:?}: {:?}", loc, e)))?;
+
+    let mut chunk = BytesMut::with_capacity(4 + serialized.len());
+    chunk.put_u32(serialized.len() as u32);
+    chunk.extend_from_slice(&serialized);
+    *sent += chunk.len();
+    Ok(send_chunk(tx, chunk.freeze()))
+}
 
-    let fetch = match Fetch::parse_payload(&mut bytes) {
-        Ok(fetch) => *fetch,
-        Err(e) => {
-            println!("Failed to parse Fetch request: {:?}", e);
-            return Ok(Box::new(warp::reply::with_status(
-                format!("Failed to parse Fetch request: {:?}", e),
-                warp::http::StatusCode::BAD_REQUEST,
-            )));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::fixtures::{frag, index};
+    use moqtail::model::common::location::Location;
+    use moqtail::model::common::tuple::Tuple;
+    use moqtail::model::control::constant::{FetchType, FilterType};
+    use moqtail::model::control::fetch::StandAloneFetchProps;
+    use moqtail::model::control::subscribe::Subscribe;
+    use warp::http::StatusCode;
+
+    fn range(start: (u64, u32), end: (u64, u32), clamp: bool) -> RangeQuery {
+        RangeQuery {
+            start_group_id: start.0,
+            start_object_id: start.1,
+            end_group_id: end.0,
+            end_object_id: end.1,
+            clamp,
         }
-    };
+    }
 
-    println!("Parsed Fetch request: {:?}", fetch);
+    // Group 0 holds objects 0-2, group 1 objects 0-1 and group 2 object 0.
+    fn three_group_index() -> indexer::Mp4Index {
+        index(vec![
+            frag(1, 0, 0, 0),
+            frag(2, 0, 0, 1),
+            frag(1, 500, 0, 2),
+            frag(1, 1000, 1, 0),
+            frag(2, 1000, 1, 1),
+            frag(1, 2000, 2, 0),
+        ])
+    }
 
-    // For now, we only support StandAlone fetch requests
-    let standalone_props = match &fetch.standalone_fetch_props {
-        Some(props) => props,
-        None => {
-            return Ok(Box::new(warp::reply::with_status(
-                "Only StandAlone fetch requests are supported".to_string(),
-                warp::http::StatusCode::BAD_REQUEST,
-            )));
+    #[test]
+    fn resolve_range_returns_the_fragments_and_their_span() {
+        let idx = three_group_index();
+        let cases = [
+            (range((0, 0), (2, 0), false), 0..6, "0:0-2:0/0:0-2:0"),
+            (range((0, 1), (1, 0), false), 1..4, "0:1-1:0/0:0-2:0"),
+            // Object IDs past either end of an edge group trim the range
+            (range((0, 9), (1, 7), false), 3..5, "1:0-1:1/0:0-2:0"),
+            (range((1, 1), (2, 9), false), 4..6, "1:1-2:0/0:0-2:0"),
+            // Clamp trims groups beyond the index as well
+            (range((0, 2), (7, 0), true), 2..6, "0:2-2:0/0:0-2:0"),
+        ];
+        for (query, frags, object_range) in cases {
+            let (got, header) = resolve_range(&query, &idx).unwrap();
+            assert_eq!((got, header.as_str()), (frags, object_range));
         }
-    };
+    }
 
-    // Extract start and end locations
-    let start_group = standalone_props.start_location.group;
-    let start_object = standalone_props.start_location.object;
-    let end_group = standalone_props.end_location.group;
-    let end_object = standalone_props.end_location.object;
+    #[test]
+    fn resolve_range_rejects_ranges_it_cannot_satisfy() {
+        let idx = three_group_index();
+        let inverted = [range((1, 0), (0, 5), false), range((1, 3), (1, 2), true)];
+        for query in inverted {
+            let err = resolve_range(&query, &idx).unwrap_err();
+            assert!(
+                matches!(err, PublisherError::InvertedRange { .. }),
+                "{err:?}"
+            );
+            assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        }
 
-    println!(
-        "Fetch range: group {}:{} → {}:{}",
-        start_group, start_object, end_group, end_object
-    );
+        let unsatisfiable = [
+            range((0, 0), (3, 0), false),
+            range((3, 0), (4, 0), true),
+            range((2, 5), (2, 9), false),
+            // Inside the groups but past the objects of group 1
+            range((1, 2), (1, 9), false),
+        ];
+        for query in unsatisfiable {
+            let err = resolve_range(&query, &idx).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    PublisherError::RangeNotSatisfiable {
+                        available: Some(_),
+                        ..
+                    }
+                ),
+                "{err:?}"
+            );
+            assert_eq!(err.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        }
 
-    let mut file = File::open(&*mp4_path).unwrap();
-    let mut response_bytes = BytesMut::new();
-
-    // First, serialize and add the init segment as a FetchObject
-    let init_len = (idx.init.end - idx.init.start) as usize;
-    let mut init_buf = vec![0u8; init_len];
-    file.seek(SeekFrom::Start(idx.init.start)).unwrap();
-    file.read_exact(&mut init_buf).unwrap();
-
-    let init_fetch_object = FetchObject {
-        group_id: 0, // Init segment is typically group 0
-        subgroup_id: 0,
-        object_id: 0, // Init segment is typically object 0
-        publisher_priority: 128,
-        extension_headers: None,
-        object_status: None,
-        payload: Some(Bytes::from(init_buf)),
-    };
+        let empty = index(vec![]);
+        assert!(matches!(
+            resolve_range(&range((0, 0), (0, 0), true), &empty),
+            Err(PublisherError::RangeNotSatisfiable {
+                available: None,
+                ..
+            })
+        ));
+    }
 
-    match init_fetch_object.serialize() {
-        Ok(serialized_init) => {
-            // Add length prefix for the init object
-            response_bytes.put_u32(serialized_init.len() as u32);
-            response_bytes.extend_from_slice(&serialized_init);
+    fn standalone_fetch() -> Fetch {
+        Fetch {
+            request_id: 7,
+            subscriber_priority: 1,
+            group_order: GroupOrder::Descending,
+            fetch_type: FetchType::StandAlone,
+            standalone_fetch_props: Some(StandAloneFetchProps {
+                track_namespace: Tuple::from_utf8_path("moqtail"),
+                track_name: "demo".to_string(),
+                start_location: Location::new(2, 0),
+                end_location: Location::new(5, 3),
+            }),
+            joining_fetch_props: None,
+            parameters: vec![],
         }
-        Err(e) => {
-            println!("Failed to serialize init FetchObject: {:?}", e);
-            return Ok(Box::new(warp::reply::with_status(
-                format!("Failed to serialize init FetchObject: {:?}", e),
-                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-            )));
+    }
+
+    #[test]
+    fn decode_fetch_round_trips_serialized_fetch() {
+        let fetch = standalone_fetch();
+        let decoded = decode_fetch(fetch.serialize().unwrap()).unwrap();
+
+        assert_eq!(decoded.request_id, 7);
+        assert_eq!(decoded.group_order, GroupOrder::Descending);
+        assert_eq!(decoded.fetch_type, FetchType::StandAlone);
+        let props = decoded.standalone_fetch_props.unwrap();
+        assert_eq!(props.track_name, "demo");
+        assert_eq!(props.start_location, Location::new(2, 0));
+        assert_eq!(props.end_location, Location::new(5, 3));
+    }
+
+    /// `body` with the 16-bit payload length of its framing moved by `by`,
+    /// and `extra` appended to the payload.
+    fn reframed(body: Bytes, by: i32, extra: &[u8]) -> Bytes {
+        let at = 1usize << (body[0] >> 6);
+        let mut body = BytesMut::from(&body[..]);
+        let declared = u16::from_be_bytes([body[at], body[at + 1]]) as i32 + by;
+        body[at..at + 2].copy_from_slice(&(declared as u16).to_be_bytes());
+        body.extend_from_slice(extra);
+        body.freeze()
+    }
+
+    fn rejects(body: Bytes) -> String {
+        match decode_fetch(body) {
+            Err(PublisherError::BadRequest(reason)) => reason,
+            other => panic!("decoded {:?}", other.map(|f| f.request_id)),
         }
     }
 
-    // Now serialize and add requested fragments as FetchObjects
-    for frag in &idx.frags {
-        let in_range = if start_group == end_group {
-            frag.group == start_group
-                && frag.object as u64 >= start_object
-                && frag.object as u64 <= end_object
-        } else {
-            (frag.group == start_group && frag.object as u64 >= start_object)
-                || (frag.group > start_group && frag.group < end_group)
-                || (frag.group == end_group && frag.object as u64 <= end_object)
+    #[test]
+    fn decode_fetch_rejects_other_control_messages() {
+        let subscribe = Subscribe {
+            request_id: 2,
+            track_namespace: Tuple::from_utf8_path("moqtail"),
+            track_name: "demo".to_string(),
+            subscriber_priority: 1,
+            group_order: GroupOrder::Original,
+            forward: true,
+            filter_type: FilterType::LatestObject,
+            start_location: None,
+            end_group: None,
+            subscribe_parameters: vec![],
         };
+        let reason = rejects(subscribe.serialize().unwrap());
+        assert!(reason.starts_with("Expected a FETCH"), "{reason}");
+    }
 
-        if in_range {
-            let moof_size = frag.mdat_start - frag.moof_start;
-            let total_size = moof_size + frag.mdat_size;
-            let mut frag_buf = vec![0u8; total_size as usize];
-            file.seek(SeekFrom::Start(frag.moof_start)).unwrap();
-            file.read_exact(&mut frag_buf).unwrap();
-
-            let frag_fetch_object = FetchObject {
-                group_id: frag.group,
-                subgroup_id: 0, // Assuming subgroup 0 for simplicity
-                object_id: frag.object as u64,
-                publisher_priority: 128,
-                extension_headers: None,
-                object_status: None,
-                payload: Some(Bytes::from(frag_buf)),
-            };
-
-            match frag_fetch_object.serialize() {
-                Ok(serialized_frag) => {
-                    // Add length prefix for each fragment object
-                    response_bytes.put_u32(serialized_frag.len() as u32);
-                    response_bytes.extend_from_slice(&serialized_frag);
-                }
-                Err(e) => {
-                    println!("Failed to serialize fragment FetchObject: {:?}", e);
-                    return Ok(Box::new(warp::reply::with_status(
-                        format!("Failed to serialize fragment FetchObject: {:?}", e),
-                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    )));
-                }
-            }
-        }
+    #[test]
+    fn decode_fetch_rejects_a_truncated_fetch() {
+        let body = standalone_fetch().serialize().unwrap();
+        rejects(body.slice(..body.len() - 1));
+        // The declared length runs past the end of the body
+        rejects(reframed(body, 1, &[]));
     }
 
-    println!(
-        "Sending {} bytes of serialized FetchObjects",
-        response_bytes.len()
-    );
+    #[test]
+    fn decode_fetch_rejects_a_payload_longer_than_the_fetch() {
+        let body = standalone_fetch().serialize().unwrap();
+        rejects(reframed(body, 1, &[0]));
+    }
 
-    Ok(Box::new(warp::reply::with_header(
-        response_bytes.into_iter().collect::<Vec<u8>>(),
-        "Content-Type",
-        "application/octet-stream",
-    )))
+    #[test]
+    fn decode_fetch_rejects_trailing_bytes() {
+        let mut body = BytesMut::from(&standalone_fetch().serialize().unwrap()[..]);
+        body.put_u8(0);
+        assert!(matches!(
+            decode_fetch(body.freeze()),
+            Err(PublisherError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn decode_fetch_rejects_garbage() {
+        assert!(matches!(
+            decode_fetch(Bytes::from_static(&[0xff])),
+            Err(PublisherError::BadRequest(_))
+        ));
+    }
 }
@@ -0,0 +1,245 @@
+// Copyright 2025 The MOQtail Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! When the MOQ publisher releases each group.
+
+use crate::indexer::Mp4Index;
+use crate::tracks::TrackSource;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Gap between groups when publishing as fast as possible.
+const BURST_GAP: Duration = Duration::from_millis(10);
+/// Slowest accepted rate. Slower ones put groups further out than a
+/// `Duration` can hold.
+const MIN_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PacingPolicy {
+    /// Groups go out back to back.
+    #[default]
+    Burst,
+    /// Each group goes out when wall-clock time reaches its media time, so
+    /// the relay sees the asset the way it would see a live origin.
+    RealTime(RealTime),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RealTime {
+    /// How long before its media time a group may go out, in seconds.
+    pub lead_secs: f64,
+    /// Media time, in seconds, that is live when the publisher starts.
+    pub start_offset_secs: f64,
+    /// Media seconds played per wall-clock second.
+    pub rate: f64,
+}
+
+impl Default for RealTime {
+    fn default() -> Self {
+        RealTime {
+            lead_secs: 1.0,
+            start_offset_secs: 0.0,
+            rate: 1.0,
+        }
+    }
+}
+
+impl RealTime {
+    /// Wall-clock seconds after the publisher starts at which a group whose
+    /// media starts at `media_secs` is due. Negative for groups due at once.
+    fn due_secs(&self, media_secs: f64) -> f64 {
+        (media_secs - self.start_offset_secs) / self.rate - self.lead_secs
+    }
+
+    /// Latest media time due `elapsed_secs` after the publisher starts; the
+    /// inverse of `due_secs`.
+    fn due_media_secs(&self, elapsed_secs: f64) -> f64 {
+        self.start_offset_secs + (elapsed_secs + self.lead_secs) * self.rate
+    }
+}
+
+/// Parses `burst`, `realtime` or `realtime:<key>=<value>,...` where the keys
+/// are `lead` and `offset` (seconds) and `rate` (at least `MIN_RATE`).
+impl FromStr for PacingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, options) = match s.split_once(':') {
+            Some((mode, options)) => (mode, Some(options)),
+            None => (s, None),
+        };
+        match (mode, options) {
+            ("burst", None) => return Ok(PacingPolicy::Burst),
+            ("realtime", _) => {}
+            _ => {
+                return Err(format!(
+                    "unknown pacing policy '{s}', expected burst or realtime[:lead=<secs>,offset=<secs>,rate=<x>]"
+                ));
+            }
+        }
+
+        let mut realtime = RealTime::default();
+        for option in options.into_iter().flat_map(|o| o.split(',')) {
+            let (key, value) = option.split_once('=').ok_or_else(|| {
+                format!("expected <key>=<value>, got '{option}' in pacing policy '{s}'")
+            })?;
+            let value: f64 = match value.parse() {
+                Ok(v) if f64::is_finite(v) => v,
+                _ => return Err(format!("invalid {key} '{value}' in pacing policy '{s}'")),
+            };
+            match key {
+                "lead" if value >= 0.0 => realtime.lead_secs = value,
+                "offset" if value >= 0.0 => realtime.start_offset_secs = value,
+                "rate" if value >= MIN_RATE => realtime.rate = value,
+                "lead" | "offset" | "rate" => {
+                    return Err(format!("{key} out of range in pacing policy '{s}'"));
+                }
+                _ => return Err(format!("unknown option '{key}' in pacing policy '{s}'")),
+            }
+        }
+        Ok(PacingPolicy::RealTime(realtime))
+    }
+}
+
+/// The publisher's clock. One is shared by every subscription so they all
+/// see the same live edge.
+pub struct Pacer {
+    policy: PacingPolicy,
+    origin: Instant,
+}
+
+impl Pacer {
+    pub fn new(policy: PacingPolicy) -> Self {
+        Pacer {
+            policy,
+            origin: Instant::now(),
+        }
+    }
+
+    /// Latest media time, in seconds, whose group is due by now. `None` when
+    /// groups go out in a burst, which makes all of the media due at once.
+    pub fn now_secs(&self) -> Option<f64> {
+        match self.policy {
+            PacingPolicy::Burst => None,
+            PacingPolicy::RealTime(rt) => {
+                Some(rt.due_media_secs(self.origin.elapsed().as_secs_f64()))
+            }
+        }
+    }
+
+    /// Largest (group, object) of `source` in a group that is due by now:
+    /// the live edge relative SUBSCRIBE filters start from.
+    pub fn live_location(&self, idx: &Mp4Index, source: TrackSource) -> Option<(u64, u64)> {
+        match self.now_secs() {
+            None => source.last_location(idx),
+            Some(now) => source.last_location_at(idx, now),
+        }
+    }
+
+    /// Waits until a group whose media starts at `media_secs` is due.
+    pub async fn wait_for(&self, media_secs: f64) {
+        match self.policy {
+            PacingPolicy::Burst => tokio::time::sleep(BURST_GAP).await,
+            PacingPolicy::RealTime(rt) => {
+                let due = rt.due_secs(media_secs);
+                if due > 0.0 {
+                    tokio::time::sleep_until(self.origin + Duration::from_secs_f64(due)).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn realtime(lead_secs: f64, start_offset_secs: f64, rate: f64) -> RealTime {
+        RealTime {
+            lead_secs,
+            start_offset_secs,
+            rate,
+        }
+    }
+
+    #[test]
+    fn lead_releases_groups_early() {
+        let rt = realtime(1.0, 0.0, 1.0);
+        assert_eq!(rt.due_secs(0.0), -1.0);
+        assert_eq!(rt.due_secs(1.0), 0.0);
+        assert_eq!(rt.due_secs(10.0), 9.0);
+        assert_eq!(rt.due_media_secs(0.0), 1.0);
+        assert_eq!(rt.due_media_secs(9.0), 10.0);
+    }
+
+    #[test]
+    fn offset_starts_live_partway_into_the_media() {
+        let rt = realtime(0.0, 30.0, 1.0);
+        // Everything before the offset is due at once
+        assert_eq!(rt.due_secs(12.0), -18.0);
+        assert_eq!(rt.due_secs(30.0), 0.0);
+        assert_eq!(rt.due_secs(45.0), 15.0);
+        assert_eq!(rt.due_media_secs(15.0), 45.0);
+    }
+
+    #[test]
+    fn rate_scales_media_time() {
+        let rt = realtime(0.5, 10.0, 2.0);
+        assert_eq!(rt.due_secs(20.0), 4.5);
+        assert_eq!(rt.due_media_secs(4.5), 20.0);
+        assert_eq!(rt.due_media_secs(0.0), 11.0);
+    }
+
+    #[test]
+    fn due_media_secs_inverts_due_secs() {
+        for rt in [
+            realtime(1.0, 0.0, 1.0),
+            realtime(2.5, 7.0, 0.5),
+            realtime(0.0, 3.0, 4.0),
+        ] {
+            for media_secs in [0.0, 1.25, 60.0, 3600.5] {
+                let back = rt.due_media_secs(rt.due_secs(media_secs));
+                assert!((back - media_secs).abs() < 1e-9, "{rt:?} at {media_secs}");
+            }
+        }
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("burst".parse(), Ok(PacingPolicy::Burst));
+        assert_eq!(
+            "realtime".parse(),
+            Ok(PacingPolicy::RealTime(RealTime::default()))
+        );
+        assert_eq!(
+            "realtime:lead=2,offset=30,rate=1.5".parse(),
+            Ok(PacingPolicy::RealTime(realtime(2.0, 30.0, 1.5)))
+        );
+        assert_eq!(
+            "realtime:rate=0.01".parse(),
+            Ok(PacingPolicy::RealTime(realtime(1.0, 0.0, MIN_RATE)))
+        );
+        for bad in [
+            "",
+            "burst:lead=1",
+            "realtime:rate=0",
+            "realtime:rate=1e-20",
+            "realtime:lead=-1",
+            "realtime:speed=2",
+        ] {
+            assert!(bad.parse::<PacingPolicy>().is_err(), "{bad:?} parsed");
+        }
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright 2025 The MOQtail Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Subscription state shared by the MOQ session and the HTTP routes, and
+//! resolution of SUBSCRIBE filters and FETCH requests (standalone or joining)
+//! against it.
+
+use moqtail::model::control::constant::{FetchType, FilterType};
+use moqtail::model::control::fetch::Fetch;
+use moqtail::model::control::subscribe::Subscribe;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Subscriptions the relay holds on this publisher, by request ID.
+#[derive(Default)]
+pub struct Subscriptions {
+    requests: Mutex<HashMap<u64, Subscription>>,
+}
+
+/// An accepted SUBSCRIBE, as joining FETCHes see it.
+struct Subscription {
+    track_name: String,
+    /// Largest (group, object) its SUBSCRIBE_OK reported. Joining FETCHes
+    /// end here, so they meet the subscription without a gap or overlap.
+    largest: Option<(u64, u64)>,
+}
+
+/// A FETCH resolved to a track and an inclusive (group, object) range. The
+/// wire end location of a standalone FETCH is exclusive, with object 0
+/// standing for the whole end group; `end` has that already applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchTarget {
+    pub track_name: String,
+    pub start: (u64, u64),
+    pub end: (u64, u64),
+}
+
+/// The part of a track a SUBSCRIBE asks for, resolved from its filter
+/// against the track's live edge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscribeWindow {
+    /// First (group, object) to publish.
+    pub start: (u64, u64),
+    /// Last group to publish, inclusive; `None` is open-ended.
+    pub end_group: Option<u64>,
+    /// Largest (group, object) at the track's live edge when the SUBSCRIBE
+    /// arrived, as SUBSCRIBE_OK reports it.
+    pub largest: Option<(u64, u64)>,
+}
+
+impl SubscribeWindow {
+    pub fn contains(&self, group: u64, object: u64) -> bool {
+        (group, object) >= self.start && self.end_group.is_none_or(|end| group <= end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchResolveError {
+    /// The FETCH lacks the properties its type requires.
+    Malformed(String),
+    /// A joining FETCH names a subscription this publisher does not hold.
+    UnknownSubscription(u64),
+    /// The joined subscription began before anything was published.
+    NoObjects(u64),
+}
+
+impl fmt::Display for FetchResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchResolveError::Malformed(msg) => f.write_str(msg),
+            FetchResolveError::UnknownSubscription(id) => {
+                write!(f, "Joining FETCH names unknown subscription {}", id)
+            }
+            FetchResolveError::NoObjects(id) => {
+                write!(
+                    f,
+                    "Subscription {} began before any objects were published",
+                    id
+                )
+            }
+        }
+    }
+}
+
+impl Subscriptions {
+    /// Records an accepted SUBSCRIBE with the largest location its
+    /// SUBSCRIBE_OK reported.
+    pub fn insert(&self, request_id: u64, track_name: String, largest: Option<(u64, u64)>) {
+        self.requests.lock().unwrap().insert(
+            request_id,
+            Subscription {
+                track_name,
+                largest,
+            },
+        );
+    }
+
+    pub fn remove(&self, request_id: u64) {
+        self.requests.lock().unwrap().remove(&request_id);
+    }
+
+    /// Forgets every subscription, as request IDs belong to one session.
+    pub fn clear_requests(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+
+    /// Resolves `fetch` to the track and range it covers. A joining FETCH
+    /// ends at the largest location the joined subscription's SUBSCRIBE_OK
+    /// reported and starts at object 0 of a group chosen by `joining_start`:
+    /// that many groups before the end for a relative fetch, or that group
+    /// for an absolute one.
+    pub fn resolve_fetch(&self, fetch: &Fetch) -> Result<FetchTarget, FetchResolveError> {
+        if let FetchType::StandAlone = fetch.fetch_type {
+            let props = fetch.standalone_fetch_props.as_ref().ok_or_else(|| {
+                FetchResolveError::Malformed("Standalone FETCH without its properties".to_string())
+            })?;
+            let end = match props.end_location.object {
+                0 => (props.end_location.group, u64::MAX),
+                object => (props.end_location.group, object - 1),
+            };
+            return Ok(FetchTarget {
+                track_name: props.track_name.clone(),
+                start: (props.start_location.group, props.start_location.object),
+                end,
+            });
+        }
+
+        let props = fetch.joining_fetch_props.as_ref().ok_or_else(|| {
+            FetchResolveError::Malformed("Joining FETCH without its properties".to_string())
+        })?;
+        let id = props.joining_request_id;
+        let requests = self.requests.lock().unwrap();
+        let sub = requests
+            .get(&id)
+            .ok_or(FetchResolveError::UnknownSubscription(id))?;
+        let end = sub.largest.ok_or(FetchResolveError::NoObjects(id))?;
+        let start_group = match fetch.fetch_type {
+            FetchType::AbsoluteFetch => props.joining_start.min(end.0),
+            _ => end.0.saturating_sub(props.joining_start),
+        };
+        Ok(FetchTarget {
+            track_name: sub.track_name.clone(),
+            start: (start_group, 0),
+            end,
+        })
+    }
+}
+
+/// Resolves the filter of `sub` to the window it subscribes to, given the
+/// `largest` (group, object) the track has at its live edge. Relative filters
+/// start just past it: at the next object for LatestObject, at the next group
+/// for NextGroupStart, or at the very start if the track has nothing yet.
+/// Returns why the filter is invalid otherwise.
+pub fn resolve_subscribe(
+    sub: &Subscribe,
+    largest: Option<(u64, u64)>,
+) -> Result<SubscribeWindow, String> {
+    let start_location = || {
+        sub.start_location
+            .map(|loc| (loc.group, loc.object))
+            .ok_or_else(|| format!("{:?} filter without a start location", sub.filter_type))
+    };
+    let (start, end_group) = match sub.filter_type {
+        FilterType::LatestObject => (largest.map_or((0, 0), |(g, o)| (g, o + 1)), None),
+        FilterType::NextGroupStart => (largest.map_or((0, 0), |(g, _)| (g + 1, 0)), None),
+        FilterType::AbsoluteStart => (start_location()?, None),
+        FilterType::AbsoluteRange => {
+            let start = start_location()?;
+            let end = sub
+                .end_group
+                .ok_or_else(|| "AbsoluteRange filter without an end group".to_string())?;
+            if end < start.0 {
+                return Err(format!(
+                    "AbsoluteRange ends at group {} before its start {}:{}",
+                    end, start.0, start.1
+                ));
+            }
+            (start, Some(end))
+        }
+    };
+    Ok(SubscribeWindow {
+        start,
+        end_group,
+        largest,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moqtail::model::common::location::Location;
+    use moqtail::model::common::tuple::Tuple;
+    use moqtail::model::control::constant::GroupOrder;
+    use moqtail::model::control::fetch::JoiningFetchProps;
+
+    const LIVE_EDGE: Option<(u64, u64)> = Some((3, 4));
+
+    fn subscribe(
+        filter_type: FilterType,
+        start: Option<(u64, u64)>,
+        end_group: Option<u64>,
+    ) -> Subscribe {
+        Subscribe {
+            request_id: 2,
+            track_namespace: Tuple::from_utf8_path("moqtail"),
+            track_name: "demo".to_string(),
+            subscriber_priority: 1,
+            group_order: GroupOrder::Original,
+            forward: true,
+            filter_type,
+            start_location: start.map(|(group, object)| Location::new(group, object)),
+            end_group,
+            subscribe_parameters: vec![],
+        }
+    }
+
+    fn window(start: (u64, u64), end_group: Option<u64>) -> SubscribeWindow {
+        SubscribeWindow {
+            start,
+            end_group,
+            largest: LIVE_EDGE,
+        }
+    }
+
+    #[test]
+    fn latest_object_starts_after_the_live_edge() {
+        let sub = subscribe(FilterType::LatestObject, None, None);
+        assert_eq!(resolve_subscribe(&sub, LIVE_EDGE), Ok(window((3, 5), None)));
+        assert_eq!(resolve_subscribe(&sub, None).map(|w| w.start), Ok((0, 0)));
+    }
+
+    #[test]
+    fn next_group_start_starts_at_the_next_group() {
+        let sub = subscribe(FilterType::NextGroupStart, None, None);
+        assert_eq!(resolve_subscribe(&sub, LIVE_EDGE), Ok(window((4, 0), None)));
+        assert_eq!(resolve_subscribe(&sub, None).map(|w| w.start), Ok((0, 0)));
+    }
+
+    #[test]
+    fn absolute_start_ignores_the_live_edge() {
+        let sub = subscribe(FilterType::AbsoluteStart, Some((1, 2)), None);
+        assert_eq!(resolve_subscribe(&sub, LIVE_EDGE), Ok(window((1, 2), None)));
+
+        let sub = subscribe(FilterType::AbsoluteStart, None, None);
+        assert!(resolve_subscribe(&sub, LIVE_EDGE).is_err());
+    }
+
+    #[test]
+    fn absolute_range_needs_an_end_at_or_after_its_start() {
+        let sub = subscribe(FilterType::AbsoluteRange, Some((1, 2)), Some(5));
+        assert_eq!(
+            resolve_subscribe(&sub, LIVE_EDGE),
+            Ok(window((1, 2), Some(5)))
+        );
+        let sub = subscribe(FilterType::AbsoluteRange, Some((1, 2)), Some(1));
+        assert_eq!(
+            resolve_subscribe(&sub, LIVE_EDGE),
+            Ok(window((1, 2), Some(1)))
+        );
+
+        for (start, end_group) in [
+            (Some((1, 2)), Some(0)),
+            (Some((1, 2)), None),
+            (None, Some(5)),
+        ] {
+            let sub = subscribe(FilterType::AbsoluteRange, start, end_group);
+            assert!(resolve_subscribe(&sub, LIVE_EDGE).is_err());
+        }
+    }
+
+    #[test]
+    fn window_contains_its_start_through_its_end_group() {
+        let w = window((1, 2), Some(3));
+        assert!(!w.contains(1, 1));
+        assert!(w.contains(1, 2));
+        assert!(w.contains(3, 99));
+        assert!(!w.contains(4, 0));
+        assert!(window((1, 2), None).contains(u64::MAX, 0));
+    }
+
+    fn joining(fetch_type: FetchType, joining_request_id: u64, joining_start: u64) -> Fetch {
+        Fetch {
+            request_id: 9,
+            subscriber_priority: 1,
+            group_order: GroupOrder::Original,
+            fetch_type,
+            standalone_fetch_props: None,
+            joining_fetch_props: Some(JoiningFetchProps {
+                joining_request_id,
+                joining_start,
+            }),
+            parameters: vec![],
+        }
+    }
+
+    #[test]
+    fn joining_fetch_ends_at_the_subscriptions_largest() {
+        let subscriptions = Subscriptions::default();
+        subscriptions.insert(4, "demo".to_string(), Some((10, 3)));
+
+        let target = subscriptions
+            .resolve_fetch(&joining(FetchType::RelativeFetch, 4, 2))
+            .unwrap();
+        assert_eq!(
+            target,
+            FetchTarget {
+                track_name: "demo".to_string(),
+                start: (8, 0),
+                end: (10, 3),
+            }
+        );
+
+        let target = subscriptions
+            .resolve_fetch(&joining(FetchType::AbsoluteFetch, 4, 6))
+            .unwrap();
+        assert_eq!((target.start, target.end), ((6, 0), (10, 3)));
+    }
+
+    #[test]
+    fn joining_fetch_needs_a_subscription_with_objects() {
+        let subscriptions = Subscriptions::default();
+        subscriptions.insert(4, "demo".to_string(), None);
+
+        assert_eq!(
+            subscriptions.resolve_fetch(&joining(FetchType::RelativeFetch, 4, 0)),
+            Err(FetchResolveError::NoObjects(4))
+        );
+        assert_eq!(
+            subscriptions.resolve_fetch(&joining(FetchType::RelativeFetch, 5, 0)),
+            Err(FetchResolveError::UnknownSubscription(5))
+        );
+    }
+}
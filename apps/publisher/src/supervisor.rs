@@ -0,0 +1,212 @@
+// Copyright 2025 The MOQtail Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keeps a MOQ session with the relay up, reconnecting whenever it drops.
+
+use crate::indexer::Mp4Index;
+use crate::moq_publisher_client::run_moq_publisher;
+use crate::pacing::Pacer;
+use crate::subscriptions::Subscriptions;
+use std::collections::hash_map::RandomState;
+use std::fmt::Write;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Wait before the first reconnect. It doubles after every session that
+/// fails before it is established, up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Counters for the relay session, served on `/metrics`.
+#[derive(Default)]
+pub struct SessionMetrics {
+    /// Connection attempts, the first one included.
+    connect_attempts: AtomicU64,
+    /// Sessions that completed setup and PUBLISH_NAMESPACE.
+    sessions_established: AtomicU64,
+    /// Sessions, established or not, that ended in an error.
+    session_failures: AtomicU64,
+    connected: AtomicBool,
+}
+
+impl SessionMetrics {
+    /// Marks the current session as set up and announced.
+    pub fn session_established(&self) {
+        self.sessions_established.fetch_add(1, Ordering::Relaxed);
+        self.connected.store(true, Ordering::Relaxed);
+    }
+
+    /// The counters in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "moq_connect_attempts_total",
+                "counter",
+                "Connection attempts to the relay",
+                self.connect_attempts.load(Ordering::Relaxed),
+            ),
+            (
+                "moq_sessions_established_total",
+                "counter",
+                "Relay sessions that completed setup and namespace announcement",
+                self.sessions_established.load(Ordering::Relaxed),
+            ),
+            (
+                "moq_session_failures_total",
+                "counter",
+                "Relay sessions that ended in an error",
+                self.session_failures.load(Ordering::Relaxed),
+            ),
+            (
+                "moq_connected",
+                "gauge",
+                "Whether a relay session is currently established",
+                self.connected.load(Ordering::Relaxed) as u64,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            );
+        }
+        out
+    }
+}
+
+/// Runs MOQ sessions for as long as the process lives. After a session ends
+/// it reconnects with exponential backoff and jitter, which redoes
+/// CLIENT_SETUP and PUBLISH_NAMESPACE; subscriptions resume as the relay
+/// subscribes again. A session that got established resets the backoff.
+pub async fn supervise(
+    mp4_path: Arc<String>,
+    idx: Arc<Mp4Index>,
+    subscriptions: Arc<Subscriptions>,
+    pacer: Arc<Pacer>,
+    metrics: Arc<SessionMetrics>,
+) {
+    let mut backoff = None;
+    loop {
+        let attempt = metrics.connect_attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        let established = metrics.sessions_established.load(Ordering::Relaxed);
+        info!("Connecting to relay (attempt {})", attempt);
+
+        let result = run_moq_publisher(
+            mp4_path.clone(),
+            idx.clone(),
+            subscriptions.clone(),
+            pacer.clone(),
+            metrics.clone(),
+        )
+        .await;
+        metrics.connected.store(false, Ordering::Relaxed);
+        match result {
+            Ok(()) => warn!("MOQ session ended"),
+            Err(e) => {
+                metrics.session_failures.fetch_add(1, Ordering::Relaxed);
+                error!("MOQ session failed: {:?}", e);
+            }
+        }
+
+        let wait = next_backoff(
+            backoff,
+            metrics.sessions_established.load(Ordering::Relaxed) > established,
+        );
+        backoff = Some(wait);
+        let delay = wait / 2 + jitter(wait / 2);
+        info!("Reconnecting to relay in {:?}", delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The backoff after a session ends, given the one used after the session
+/// before it: back to `INITIAL_BACKOFF` if this session got established,
+/// doubled up to `MAX_BACKOFF` otherwise.
+fn next_backoff(previous: Option<Duration>, established: bool) -> Duration {
+    match previous {
+        Some(previous) if !established => (previous * 2).min(MAX_BACKOFF),
+        _ => INITIAL_BACKOFF,
+    }
+}
+
+/// A pseudo-random duration up to `max`, so publishers that lost the same
+/// relay do not all come back at once.
+fn jitter(max: Duration) -> Duration {
+    let random = RandomState::new().hash_one(Instant::now());
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_stays_within_its_bound() {
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(jitter(INITIAL_BACKOFF) <= INITIAL_BACKOFF);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_until_its_cap() {
+        let mut backoff = None;
+        let mut waits = vec![];
+        for _ in 0..9 {
+            let wait = next_backoff(backoff, false);
+            waits.push(wait.as_millis());
+            backoff = Some(wait);
+        }
+        assert_eq!(
+            waits,
+            [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000]
+        );
+    }
+
+    #[test]
+    fn established_session_resets_the_backoff() {
+        assert_eq!(next_backoff(Some(MAX_BACKOFF), true), INITIAL_BACKOFF);
+        assert_eq!(next_backoff(None, true), INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn render_reports_every_counter() {
+        let metrics = SessionMetrics::default();
+        metrics.connect_attempts.fetch_add(3, Ordering::Relaxed);
+        metrics.session_failures.fetch_add(2, Ordering::Relaxed);
+        metrics.session_established();
+
+        let out = metrics.render();
+        for line in [
+            "# TYPE moq_connect_attempts_total counter",
+            "moq_connect_attempts_total 3",
+            "moq_sessions_established_total 1",
+            "moq_session_failures_total 2",
+            "# TYPE moq_connected gauge",
+            "moq_connected 1",
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                out
+            );
+        }
+        assert_eq!(out.lines().count(), 12);
+    }
+}
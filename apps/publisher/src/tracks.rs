@@ -0,0 +1,377 @@
+// Copyright 2025 The MOQtail Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The tracks this publisher hosts and how a FETCH maps onto their objects,
+//! shared by the relay session and the HTTP routes.
+
+use crate::indexer::{self, LocationSpan, ObjectLocation};
+use crate::subscriptions::FetchTarget;
+use bytes::Bytes;
+use moqtail::model::common::tuple::Tuple;
+use moqtail::model::control::fetch::Fetch;
+use moqtail::model::data::fetch_object::FetchObject;
+use std::cmp::Reverse;
+use std::io::{Read, Seek};
+
+/// Namespace announced to the relay. `TrackSource` says what each track
+/// name under it carries.
+pub const TRACK_NAMESPACE: &str = "moqtail";
+/// The ftyp+moov init segment, published as the single object at
+/// `INIT_LOCATION` so clients can fetch and cache it apart from media groups.
+pub const INIT_TRACK: &str = "demo/init";
+pub const INIT_LOCATION: ObjectLocation = ObjectLocation {
+    group: 0,
+    subgroup: 0,
+    object: 0,
+};
+/// Fragments of every MP4 track, addressed as described on `indexer::Frag`.
+pub const MEDIA_TRACK: &str = "demo";
+/// Prefix of the tracks carrying the fragments of a single MP4 track, named
+/// by its track ID, e.g. `demo/track/1`. Objects keep the locations they
+/// have on `MEDIA_TRACK`.
+pub const SINGLE_TRACK_PREFIX: &str = "demo/track/";
+
+/// What a published track carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackSource {
+    /// The init segment alone.
+    Init,
+    /// Fragments of every MP4 track.
+    Media,
+    /// Fragments of the MP4 track with this ID.
+    SingleTrack(u32),
+}
+
+impl TrackSource {
+    /// Routes `track_name` under `TRACK_NAMESPACE` to what it carries, or
+    /// `None` if this publisher does not host such a track.
+    pub fn for_track(idx: &indexer::Mp4Index, track_name: &str) -> Option<TrackSource> {
+        if track_name == INIT_TRACK {
+            return Some(TrackSource::Init);
+        }
+        if track_name == MEDIA_TRACK {
+            return Some(TrackSource::Media);
+        }
+        track_name
+            .strip_prefix(SINGLE_TRACK_PREFIX)
+            .and_then(|id| id.parse().ok())
+            .filter(|id| idx.timescale.contains_key(id))
+            .map(TrackSource::SingleTrack)
+    }
+
+    /// Like `for_track`, for a track named with its namespace.
+    pub fn for_full_name(
+        idx: &indexer::Mp4Index,
+        namespace: &Tuple,
+        track_name: &str,
+    ) -> Option<TrackSource> {
+        if *namespace != Tuple::from_utf8_path(TRACK_NAMESPACE) {
+            return None;
+        }
+        TrackSource::for_track(idx, track_name)
+    }
+
+    /// Routes the track a FETCH resolved to. Joining fetches name their
+    /// track through a subscription, whose track was checked when it came in.
+    pub fn for_fetch(
+        idx: &indexer::Mp4Index,
+        fetch: &Fetch,
+        target: &FetchTarget,
+    ) -> Option<TrackSource> {
+        match &fetch.standalone_fetch_props {
+            Some(props) => {
+                TrackSource::for_full_name(idx, &props.track_namespace, &target.track_name)
+            }
+            None => TrackSource::for_track(idx, &target.track_name),
+        }
+    }
+
+    /// Location of the last object the track carries.
+    pub fn last_location(self, idx: &indexer::Mp4Index) -> Option<(u64, u64)> {
+        let loc = match self {
+            TrackSource::Init => INIT_LOCATION,
+            _ => idx.frags.iter().rev().find(|f| self.carries(f))?.location(),
+        };
+        Some((loc.group, loc.object))
+    }
+
+    /// Location of the last object the track carries in a group that has
+    /// started by `secs` on the presentation timeline. Groups go out whole,
+    /// so that is the group's last object, not the last fragment started.
+    pub fn last_location_at(self, idx: &indexer::Mp4Index, secs: f64) -> Option<(u64, u64)> {
+        if self == TrackSource::Init {
+            return self.last_location(idx);
+        }
+        // `frags` is in presentation order; see Mp4Index::frag_index_at
+        let started = idx.frags.partition_point(|f| idx.start_secs(f) <= secs);
+        let group = idx.frags[..started].last()?.group;
+        let i = match self {
+            TrackSource::SingleTrack(id) => {
+                let track = idx.track_frags.get(&id)?;
+                *track[..track.partition_point(|&i| idx.frags[i].group <= group)].last()?
+            }
+            _ => idx
+                .frags
+                .partition_point(|f| f.group <= group)
+                .checked_sub(1)?,
+        };
+        let loc = idx.frags[i].location();
+        Some((loc.group, loc.object))
+    }
+
+    /// First to last location of the objects the track carries.
+    pub fn span(self, idx: &indexer::Mp4Index) -> Option<LocationSpan> {
+        let (first, last) = match self {
+            TrackSource::Init => (INIT_LOCATION, INIT_LOCATION),
+            _ => (
+                idx.frags.iter().find(|f| self.carries(f))?.location(),
+                idx.frags.iter().rev().find(|f| self.carries(f))?.location(),
+            ),
+        };
+        Some(LocationSpan::new(
+            (first.group, first.object),
+            (last.group, last.object),
+        ))
+    }
+
+    pub fn carries(self, frag: &indexer::Frag) -> bool {
+        match self {
+            TrackSource::Init => false,
+            TrackSource::Media => true,
+            TrackSource::SingleTrack(id) => frag.track_id == id,
+        }
+    }
+}
+
+/// The objects a FETCH returns, in the order they are sent.
+pub struct FetchPlan {
+    pub objects: Vec<FetchItem>,
+    /// Whether the plan runs to the last object the track will ever have.
+    pub end_of_track: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FetchItem {
+    /// The init segment, at `INIT_LOCATION`.
+    Init,
+    /// The fragment at this index in `Mp4Index::frags`.
+    Fragment(usize),
+}
+
+impl FetchItem {
+    pub fn location(self, idx: &indexer::Mp4Index) -> ObjectLocation {
+        match self {
+            FetchItem::Init => INIT_LOCATION,
+            FetchItem::Fragment(i) => idx.frags[i].location(),
+        }
+    }
+
+    pub fn read<R: Read + Seek>(
+        self,
+        idx: &indexer::Mp4Index,
+        r: &mut R,
+    ) -> std::io::Result<Vec<u8>> {
+        match self {
+            FetchItem::Init => idx.read_init(r),
+            FetchItem::Fragment(i) => idx.read_fragment(r, &idx.frags[i]),
+        }
+    }
+}
+
+impl FetchPlan {
+    /// The largest location the plan covers, as FETCH_OK reports it. Objects
+    /// are ordered by group and object; subgroups play no part.
+    pub fn largest(&self, idx: &indexer::Mp4Index) -> Option<ObjectLocation> {
+        self.objects
+            .iter()
+            .map(|item| item.location(idx))
+            .max_by_key(|loc| (loc.group, loc.object))
+    }
+}
+
+/// Lists the objects of `source` that `target` covers. With `descending`,
+/// newer groups come first while objects within a group stay in ascending
+/// order.
+pub fn plan_fetch(
+    idx: &indexer::Mp4Index,
+    source: TrackSource,
+    target: &FetchTarget,
+    descending: bool,
+) -> FetchPlan {
+    if source == TrackSource::Init {
+        let init = (INIT_LOCATION.group, INIT_LOCATION.object);
+        let covered = target.start <= init && init <= target.end;
+        return FetchPlan {
+            objects: if covered {
+                vec![FetchItem::Init]
+            } else {
+                vec![]
+            },
+            end_of_track: covered,
+        };
+    }
+
+    let range = idx.location_range(target.start, target.end);
+    let rest = &idx.frags[range.end..];
+    let mut frags: Vec<usize> = range.filter(|&i| source.carries(&idx.frags[i])).collect();
+    let end_of_track = !frags.is_empty() && !rest.iter().any(|f| source.carries(f));
+    if descending {
+        frags.sort_by_key(|&i| (Reverse(idx.frags[i].group), idx.frags[i].object));
+    }
+    FetchPlan {
+        objects: frags.into_iter().map(FetchItem::Fragment).collect(),
+        end_of_track,
+    }
+}
+
+/// The FetchObject carrying `payload` at `loc`.
+pub fn fetch_object(loc: ObjectLocation, payload: Vec<u8>) -> FetchObject {
+    FetchObject {
+        group_id: loc.group,
+        subgroup_id: loc.subgroup,
+        object_id: loc.object,
+        publisher_priority: 128,
+        extension_headers: None,
+        object_status: None,
+        payload: Some(Bytes::from(payload)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::fixtures::{frag, index};
+
+    // Groups 0 and 1 hold video (track 1) and audio (track 2); group 2 only
+    // video.
+    fn two_track_index() -> indexer::Mp4Index {
+        index(vec![
+            frag(1, 0, 0, 0),
+            frag(2, 0, 0, 1),
+            frag(1, 500, 0, 2),
+            frag(1, 1000, 1, 0),
+            frag(2, 1000, 1, 1),
+            frag(1, 1500, 1, 2),
+            frag(1, 2000, 2, 0),
+        ])
+    }
+
+    #[test]
+    fn last_location_at_covers_whole_started_groups() {
+        let idx = two_track_index();
+        assert_eq!(TrackSource::Media.last_location_at(&idx, 0.0), Some((0, 2)));
+        assert_eq!(TrackSource::Media.last_location_at(&idx, 1.2), Some((1, 2)));
+        assert_eq!(
+            TrackSource::Media.last_location_at(&idx, 99.0),
+            Some((2, 0))
+        );
+        assert_eq!(TrackSource::Media.last_location_at(&idx, -1.0), None);
+    }
+
+    #[test]
+    fn last_location_at_keeps_to_a_single_track() {
+        let idx = two_track_index();
+        let audio = TrackSource::SingleTrack(2);
+        assert_eq!(audio.last_location_at(&idx, 0.7), Some((0, 1)));
+        assert_eq!(audio.last_location_at(&idx, 2.5), Some((1, 1)));
+        assert_eq!(
+            TrackSource::SingleTrack(3).last_location_at(&idx, 2.5),
+            None
+        );
+        assert_eq!(TrackSource::Init.last_location_at(&idx, -1.0), Some((0, 0)));
+    }
+
+    fn target(start: (u64, u64), end: (u64, u64)) -> FetchTarget {
+        FetchTarget {
+            track_name: MEDIA_TRACK.to_string(),
+            start,
+            end,
+        }
+    }
+
+    fn locations(idx: &indexer::Mp4Index, plan: &FetchPlan) -> Vec<(u64, u64)> {
+        plan.objects
+            .iter()
+            .map(|item| {
+                let loc = item.location(idx);
+                (loc.group, loc.object)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plan_fetch_lists_the_range_in_ascending_order() {
+        let idx = two_track_index();
+        let plan = plan_fetch(&idx, TrackSource::Media, &target((0, 2), (1, 1)), false);
+        assert_eq!(locations(&idx, &plan), [(0, 2), (1, 0), (1, 1)]);
+        assert!(!plan.end_of_track);
+        assert_eq!(
+            plan.largest(&idx).map(|l| (l.group, l.object)),
+            Some((1, 1))
+        );
+    }
+
+    #[test]
+    fn plan_fetch_descending_reverses_groups_only() {
+        let idx = two_track_index();
+        let plan = plan_fetch(
+            &idx,
+            TrackSource::Media,
+            &target((0, 1), (2, u64::MAX)),
+            true,
+        );
+        assert_eq!(
+            locations(&idx, &plan),
+            [(2, 0), (1, 0), (1, 1), (1, 2), (0, 1), (0, 2)]
+        );
+        assert!(plan.end_of_track);
+        assert_eq!(
+            plan.largest(&idx).map(|l| (l.group, l.object)),
+            Some((2, 0))
+        );
+    }
+
+    #[test]
+    fn plan_fetch_ends_a_single_track_at_its_last_fragment() {
+        let idx = two_track_index();
+        let audio = TrackSource::SingleTrack(2);
+
+        // Audio ends in group 1, though video goes on into group 2
+        let plan = plan_fetch(&idx, audio, &target((0, 0), (1, u64::MAX)), false);
+        assert_eq!(locations(&idx, &plan), [(0, 1), (1, 1)]);
+        assert!(plan.end_of_track);
+
+        let plan = plan_fetch(&idx, audio, &target((0, 0), (0, u64::MAX)), false);
+        assert_eq!(locations(&idx, &plan), [(0, 1)]);
+        assert!(!plan.end_of_track);
+
+        let plan = plan_fetch(&idx, audio, &target((2, 0), (2, u64::MAX)), false);
+        assert!(plan.objects.is_empty());
+        assert!(!plan.end_of_track);
+        assert!(plan.largest(&idx).is_none());
+    }
+
+    #[test]
+    fn plan_fetch_of_the_init_track_holds_the_init_segment() {
+        let idx = two_track_index();
+        let plan = plan_fetch(&idx, TrackSource::Init, &target((0, 0), (0, 0)), true);
+        assert!(matches!(plan.objects[..], [FetchItem::Init]));
+        assert!(plan.end_of_track);
+        assert_eq!(plan.largest(&idx), Some(INIT_LOCATION));
+
+        let plan = plan_fetch(&idx, TrackSource::Init, &target((0, 1), (3, 0)), false);
+        assert!(plan.objects.is_empty());
+        assert!(!plan.end_of_track);
+    }
+}